@@ -0,0 +1,75 @@
+/*
+the channel scheduler against the work-stealing one, on lots of jobs that do next to nothing, which
+is where workers spend most of their time getting jobs rather than running them.
+
+  cargo bench --bench pool [jobs]
+
+each case runs a few times on a fresh pool and the median is reported. the numbers only mean
+something on a machine with a few cores to spare
+*/
+
+use std::env;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use web_server::{Level, Logger, Scheduler, ThreadPool};
+
+const DEFAULT_JOBS: usize = 200_000;
+const WORKERS: usize = 4;
+const ROUNDS: usize = 5;
+
+fn main() {
+  let jobs = env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(DEFAULT_JOBS);
+  Logger::stdout().with_level(Level::Warn).install(); // not "shutting down all workers" every round
+  let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+
+  println!("{} tiny jobs on {} workers ({} cores), median of {} rounds\n", jobs, WORKERS, cores, ROUNDS);
+  println!("{:<20} {:>14} {:>14} {:>8}", "", "channel", "work-stealing", "speedup");
+  for submitters in [1, WORKERS] {
+    let channel = median(|| run(Scheduler::Channel, jobs, submitters));
+    let stealing = median(|| run(Scheduler::WorkStealing, jobs, submitters));
+    println!("{:<20} {:>14} {:>14} {:>7.2}x",
+             format!("{} submitter{}", submitters, if submitters == 1 { "" } else { "s" }),
+             rate(jobs, channel), rate(jobs, stealing), channel.as_secs_f64() / stealing.as_secs_f64());
+  }
+}
+
+// how long a fresh pool takes to run `jobs` jobs queued from `submitters` threads at once
+fn run(scheduler: Scheduler, jobs: usize, submitters: usize) -> Duration {
+  let pool = ThreadPool::builder(WORKERS).scheduler(scheduler).build().unwrap();
+  let done = Arc::new(AtomicUsize::new(0));
+
+  let started = Instant::now();
+  thread::scope(|scope| {
+    for _ in 0..submitters {
+      scope.spawn(|| {
+        for i in 0..jobs / submitters {
+          let done = Arc::clone(&done);
+          pool.execute(move || {
+            black_box(i);
+            done.fetch_add(1, Ordering::Relaxed);
+          }).unwrap();
+        }
+      });
+    }
+  });
+  while done.load(Ordering::Relaxed) < jobs / submitters * submitters {
+    thread::yield_now();
+  }
+  let elapsed = started.elapsed();
+  drop(pool);
+  elapsed
+}
+
+fn median(mut measure: impl FnMut() -> Duration) -> Duration {
+  let mut times: Vec<Duration> = (0..ROUNDS).map(|_| measure()).collect();
+  times.sort();
+  times[ROUNDS / 2]
+}
+
+fn rate(jobs: usize, elapsed: Duration) -> String {
+  format!("{:.2}M jobs/s", jobs as f64 / elapsed.as_secs_f64() / 1_000_000.0)
+}
@@ -1,52 +1,73 @@
 /*
-a single threaded web server following the guide in chapter 20 of The Rust Book
+a multi threaded web server following the guide in chapter 20 of The Rust Book
 */
 
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::fs;
+use std::env;
+use std::process;
 
-use web_server::ThreadPool; // our custom struct
+use web_server::compress::Compression;
+use web_server::config::{self, Config};
+use web_server::middleware::{RequestId, Timing};
+use web_server::{log_event, Level, Message, Response, Router};
 
 fn main() {
-  let listener = TcpListener::bind("127.0.0.1:7878").unwrap(); // listen for requests at address
- 
-  let pool = ThreadPool::new(4); // our custom ThreadPool struct. max of 4 threads
-
-  //for stream in listener.incoming() { // continuously handle all page requests 
-  for stream in listener.incoming().take(2) { // only take two requests, then exit for-loop
-    let stream = stream.unwrap();
-
-    //handle_connection(stream); // for single threaded version
-
-    pool.execute(|| {
-        handle_connection(stream);
-    });
+  // settings come from server.toml (or the file given with --config) and then the command line
+  let args: Vec<String> = env::args().skip(1).collect();
+  if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+    print!("{}", config::USAGE);
+    return;
   }
-  println!("Shutting down server");
-  // after this is printed you'll see the cleanup messages for when threadpool drops out of scope
-}
+  let config = Config::from_args(&args).unwrap_or_else(|e| {
+    eprintln!("{}\n\n{}", e, config::USAGE);
+    process::exit(2);
+  });
+  config.logger().unwrap_or_else(|e| {
+    eprintln!("couldn't open log file: {}", e);
+    process::exit(1);
+  }).install();
 
-fn handle_connection(mut stream: TcpStream) {
-  let mut buffer = [0; 512]; // allocate a buffer for writing our read request into
+  let files = config.static_files();
 
-  stream.read(&mut buffer).unwrap();
+  let mut router = Router::new();
+  router.get("/hello/:name", |req| {
+    Response::text(format!("Hello, {}!\n", req.param("name").unwrap_or("stranger")))
+  });
+  router.websocket("/echo", |_req, mut ws| { // try it with `new WebSocket("ws://127.0.0.1:7878/echo")`
+    while let Ok(message) = ws.recv() {
+      let echoed = match message {
+        Message::Text(_) | Message::Binary(_) => ws.send(message),
+        Message::Close(_) => break,
+        _ => Ok(()), // pings are answered for us
+      };
+      if echoed.is_err() {
+        break;
+      }
+    }
+  });
+  router.get("/*path", move |req| files.serve(req)); // anything else comes from the document root
 
-  //println!("Request: {}", String::from_utf8_lossy(&buffer[..])); // 'lossy' replaces unknown chars
+  let mut server = config.server(router).unwrap_or_else(|e| {
+    eprintln!("couldn't listen on {}: {}", config.urls().join(", "), e);
+    process::exit(1);
+  });
+  if config.compression {
+    server = server.middleware(Compression::new()); // added first so it sees the finished response last
+  }
+  let server = server
+    .middleware(RequestId::new()) // X-Request-Id on every response
+    .middleware(Timing); // and X-Response-Time
+  // proxies go last so the request id and timing cover forwarded requests too
+  let server = config.proxies().into_iter().fold(server, |server, proxy| server.middleware(proxy));
+  // the bound addresses rather than the configured ones, which could have asked for port 0
+  log_event!(Level::Info, "Listening on {}", server.urls().unwrap_or_else(|_| config.urls()).join(", "));
 
-  let get = b"GET / HTTP/1.1\r\n"; //'b' means interpret as bytes. GET request for '/' page
+  // ctrl-c (SIGINT) and SIGTERM stop the accept loop and let in-flight requests finish
+  let shutdown = server.shutdown_handle();
+  ctrlc::set_handler(move || {
+    log_event!(Level::Info, "Received shutdown signal");
+    shutdown.shutdown();
+  }).expect("failed to install signal handler");
 
-  let (status_line, file_name) = if buffer.starts_with(get) {
-      ("HTTP/1.1 200 OK\r\n\r\n", "hello.html") // must be implicit return
-    }
-    else {
-      ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
-
-    let contents = fs::read_to_string(file_name).unwrap();
-    let response = format!("{}{}",status_line, contents);
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap(); 
+  server.run().unwrap();
+  // after run() returns the workers have been told to terminate and joined
 }
-
@@ -0,0 +1,145 @@
+/*
+a small json api on top of web_server: a todo list kept in memory, gone when the server stops.
+
+  cargo run --bin todos [address]      (defaults to 127.0.0.1:7879)
+
+  curl localhost:7879/todos
+  curl localhost:7879/todos -H 'Content-Type: application/json' -d '{"title": "water the plants"}'
+  curl localhost:7879/todos/1 -X PUT -H 'Content-Type: application/json' \
+    -d '{"title": "water the plants", "done": true}'
+  curl localhost:7879/todos/1 -X DELETE
+*/
+
+use std::collections::BTreeMap;
+use std::env;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use web_server::json;
+use web_server::middleware::RequestId;
+use web_server::{log_event, Level, Request, Response, Router, Server};
+
+#[derive(Debug, Clone, Serialize)]
+struct Todo {
+  id: u64,
+  title: String,
+  done: bool,
+}
+
+// what clients send to create or replace a todo. the id always comes from us
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TodoInput {
+  title: String,
+  #[serde(default)]
+  done: bool,
+}
+
+#[derive(Default)]
+struct Todos {
+  next_id: u64,
+  items: BTreeMap<u64, Todo>, // ordered, so listing comes out oldest first
+}
+
+type Store = Arc<Mutex<Todos>>;
+
+fn main() {
+  let addr = env::args().nth(1).unwrap_or_else(|| String::from("127.0.0.1:7879"));
+  let store = Store::default();
+
+  let mut router = Router::new();
+  let todos = Arc::clone(&store);
+  router.get("/todos", move |_req| list(&todos));
+  let todos = Arc::clone(&store);
+  router.post("/todos", move |req| create(&todos, req));
+  let todos = Arc::clone(&store);
+  router.get("/todos/:id", move |req| show(&todos, req));
+  let todos = Arc::clone(&store);
+  router.put("/todos/:id", move |req| update(&todos, req));
+  let todos = Arc::clone(&store);
+  router.delete("/todos/:id", move |req| remove(&todos, req));
+  router.not_found(|_req| json::error(404, "no such route"));
+  router.method_not_allowed(|_req| json::error(405, "method not allowed"));
+
+  let server = Server::bind(&addr, router).unwrap_or_else(|e| {
+    eprintln!("couldn't listen on {}: {}", addr, e);
+    process::exit(1);
+  }).middleware(RequestId::new());
+  log_event!(Level::Info, "Todo api listening on http://{}/todos", addr);
+
+  let shutdown = server.shutdown_handle();
+  ctrlc::set_handler(move || shutdown.shutdown()).expect("failed to install signal handler");
+  server.run().unwrap();
+}
+
+fn list(store: &Store) -> Response {
+  let todos = store.lock().unwrap();
+  Response::json(&todos.items.values().collect::<Vec<_>>())
+}
+
+fn create(store: &Store, req: &Request) -> Response {
+  let input: TodoInput = match req.json() {
+    Ok(input) => input,
+    Err(e) => return e.into_response(),
+  };
+  let mut todos = store.lock().unwrap();
+  todos.next_id += 1;
+  let todo = Todo { id: todos.next_id, title: input.title, done: input.done };
+  todos.items.insert(todo.id, todo.clone());
+  Response::json(&todo)
+    .with_status(201)
+    .with_header("Location", &format!("/todos/{}", todo.id))
+}
+
+fn show(store: &Store, req: &Request) -> Response {
+  let id = match todo_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match store.lock().unwrap().items.get(&id) {
+    Some(todo) => Response::json(todo),
+    None => not_found(id),
+  }
+}
+
+fn update(store: &Store, req: &Request) -> Response {
+  let id = match todo_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let input: TodoInput = match req.json() {
+    Ok(input) => input,
+    Err(e) => return e.into_response(),
+  };
+  match store.lock().unwrap().items.get_mut(&id) {
+    Some(todo) => {
+      todo.title = input.title;
+      todo.done = input.done;
+      Response::json(todo)
+    }
+    None => not_found(id),
+  }
+}
+
+fn remove(store: &Store, req: &Request) -> Response {
+  let id = match todo_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match store.lock().unwrap().items.remove(&id) {
+    Some(_) => Response::new(204),
+    None => not_found(id),
+  }
+}
+
+// the :id segment, or the 400 to send back when it isn't a number
+fn todo_id(req: &Request) -> Result<u64, Response> {
+  let id = req.param("id").unwrap_or("");
+  id.parse().map_err(|_| json::error(400, &format!("{:?} is not a todo id", id)))
+}
+
+fn not_found(id: u64) -> Response {
+  json::error(404, &format!("no todo with id {}", id))
+}
@@ -0,0 +1,249 @@
+/*
+gzip and deflate compression of response bodies, as middleware.
+
+the client lists the encodings it understands in Accept-Encoding, we pick the one it likes best
+and say which we used in Content-Encoding. responses that could have been compressed also get
+"Vary: Accept-Encoding", so caches don't hand a gzipped copy to a client that can't read it.
+bodies that are tiny, already compressed (images, zips...) or partial ranges are left alone
+*/
+
+use std::io;
+use std::io::prelude::*;
+
+use flate2::read::{GzEncoder, ZlibEncoder};
+use flate2::write;
+
+use crate::middleware::Middleware;
+use crate::mime;
+use crate::request::Request;
+use crate::response::{Body, Response};
+
+/// Bodies smaller than this aren't worth compressing: the gzip header alone is 18 bytes.
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+  Gzip,
+  Deflate, // the zlib format, which is what http means by "deflate"
+}
+
+impl Encoding {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Encoding::Gzip => "gzip",
+      Encoding::Deflate => "deflate",
+    }
+  }
+}
+
+/// Picks the encoding to use for a client that sent `accept_encoding`, or `None` to send the
+/// body as is. Preference values (`;q=0.5`) are honoured and gzip wins ties.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+  let mut gzip = None;
+  let mut deflate = None;
+  let mut any = None; // what "*" says about encodings that aren't named
+
+  for item in accept_encoding.split(',') {
+    let mut parts = item.split(';');
+    let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    let quality = parts
+      .filter_map(|param| param.trim().strip_prefix("q=").map(|q| q.trim().parse::<f32>().unwrap_or(0.0)))
+      .next()
+      .unwrap_or(1.0);
+    match name.as_str() {
+      "gzip" | "x-gzip" => gzip = Some(quality),
+      "deflate" => deflate = Some(quality),
+      "*" => any = Some(quality),
+      _ => {}
+    }
+  }
+
+  let gzip = gzip.or(any).unwrap_or(0.0);
+  let deflate = deflate.or(any).unwrap_or(0.0);
+  if gzip > 0.0 && gzip >= deflate {
+    Some(Encoding::Gzip)
+  } else if deflate > 0.0 {
+    Some(Encoding::Deflate)
+  } else {
+    None
+  }
+}
+
+/// Middleware that compresses response bodies for clients that accept it.
+#[derive(Debug, Clone)]
+pub struct Compression {
+  min_size: u64,
+  level: flate2::Compression,
+}
+
+impl Compression {
+  pub fn new() -> Compression {
+    Compression { min_size: DEFAULT_MIN_SIZE, level: flate2::Compression::default() }
+  }
+
+  /// Don't bother compressing bodies shorter than `bytes`. Streamed bodies of unknown length are
+  /// always compressed.
+  pub fn min_size(mut self, bytes: u64) -> Compression {
+    self.min_size = bytes;
+    self
+  }
+
+  /// How hard to try, from 0 (fastest) to 9 (smallest).
+  pub fn level(mut self, level: u32) -> Compression {
+    self.level = flate2::Compression::new(level.min(9));
+    self
+  }
+
+  // whether the representation could be compressed at all, before looking at the client
+  fn eligible(&self, response: &Response) -> bool {
+    let status_ok = response.status == 200 || (response.status >= 400 && response.status != 416);
+    let type_ok = match response.headers.get("Content-Type") {
+      Some(content_type) => !mime::is_compressed(content_type),
+      None => false, // no idea what it is, so leave it be
+    };
+    status_ok && type_ok && !response.headers.contains("Content-Encoding")
+  }
+}
+
+impl Default for Compression {
+  fn default() -> Compression {
+    Compression::new()
+  }
+}
+
+impl Middleware for Compression {
+  fn after(&self, request: &Request, mut response: Response) -> Response {
+    if !self.eligible(&response) {
+      return response;
+    }
+    // the answer depends on Accept-Encoding whether or not this client gets it compressed
+    if !response.headers.has_token("Vary", "Accept-Encoding") {
+      response.headers.append("Vary", "Accept-Encoding");
+    }
+
+    let encoding = match request.header("Accept-Encoding").and_then(negotiate) {
+      Some(encoding) => encoding,
+      None => return response,
+    };
+    if request.method == "HEAD" {
+      return response; // the body is already gone, so we can't know its compressed length
+    }
+    if matches!(response.body.len(), Some(length) if length < self.min_size) {
+      return response;
+    }
+
+    let body = std::mem::take(&mut response.body);
+    response.body = match body {
+      Body::Bytes(bytes) => match compress(&bytes, encoding, self.level) {
+        Ok(compressed) => Body::Bytes(compressed),
+        Err(_) => return response.with_body(bytes), // can't happen writing to a Vec, but send it plain
+      },
+      Body::Stream { reader, .. } => {
+        let reader: Box<dyn Read + Send> = match encoding {
+          Encoding::Gzip => Box::new(GzEncoder::new(reader, self.level)),
+          Encoding::Deflate => Box::new(ZlibEncoder::new(reader, self.level)),
+        };
+        Body::Stream { reader, length: None } // we won't know how small it got until the end
+      }
+    };
+
+    // the length and byte offsets a handler set were for the uncompressed body, and the compressed
+    // bytes may differ from one run to the next, so a strong ETag would be a lie
+    response.headers.remove("Content-Length");
+    response.headers.remove("Accept-Ranges");
+    if let Some(etag) = response.headers.get("ETag").filter(|etag| !etag.starts_with("W/")) {
+      let weak = format!("W/{}", etag);
+      response.headers.set("ETag", &weak);
+    }
+    response.with_header("Content-Encoding", encoding.as_str())
+  }
+}
+
+fn compress(bytes: &[u8], encoding: Encoding, level: flate2::Compression) -> io::Result<Vec<u8>> {
+  match encoding {
+    Encoding::Gzip => {
+      let mut encoder = write::GzEncoder::new(Vec::new(), level);
+      encoder.write_all(bytes)?;
+      encoder.finish()
+    }
+    Encoding::Deflate => {
+      let mut encoder = write::ZlibEncoder::new(Vec::new(), level);
+      encoder.write_all(bytes)?;
+      encoder.finish()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flate2::read::{GzDecoder, ZlibDecoder};
+
+  fn request(accept_encoding: &str) -> Request {
+    let text = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept_encoding);
+    Request::read_from(&mut text.as_bytes()).unwrap()
+  }
+
+  fn page() -> Response {
+    Response::html("<p>hello</p>\n".repeat(200))
+  }
+
+  #[test]
+  fn negotiation() {
+    assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("deflate"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("gzip;q=0.5, deflate;q=0.8"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("gzip;q=0, *"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("*;q=0"), None);
+    assert_eq!(negotiate("identity, br"), None);
+    assert_eq!(negotiate(""), None);
+  }
+
+  #[test]
+  fn compresses_bytes() {
+    let response = Compression::new().after(&request("gzip"), page());
+    assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+
+    let compressed = response.body.into_bytes().unwrap();
+    assert!(compressed.len() < 200);
+    let mut decoded = String::new();
+    GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, "<p>hello</p>\n".repeat(200));
+  }
+
+  #[test]
+  fn compresses_streams() {
+    let text = "streamed\n".repeat(500);
+    let response = Response::new(200)
+      .with_header("Content-Type", "text/plain")
+      .with_reader(io::Cursor::new(text.clone()), Some(text.len() as u64));
+    let response = Compression::new().after(&request("deflate"), response);
+    assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+    assert_eq!(response.body.len(), None);
+
+    let mut decoded = String::new();
+    ZlibDecoder::new(&response.body.into_bytes().unwrap()[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, text);
+  }
+
+  #[test]
+  fn leaves_some_things_alone() {
+    let compression = Compression::new();
+
+    let small = compression.after(&request("gzip"), Response::html("tiny"));
+    assert!(!small.headers.contains("Content-Encoding"));
+    assert_eq!(small.headers.get("Vary"), Some("Accept-Encoding")); // it could have been though
+
+    let image = page().with_header("Content-Type", "image/png");
+    let image = compression.after(&request("gzip"), image);
+    assert!(!image.headers.contains("Content-Encoding"));
+    assert!(!image.headers.contains("Vary"));
+
+    let partial = compression.after(&request("gzip"), page().with_status(206));
+    assert!(!partial.headers.contains("Content-Encoding"));
+
+    let unwilling = compression.after(&request("identity"), page());
+    assert!(!unwilling.headers.contains("Content-Encoding"));
+  }
+}
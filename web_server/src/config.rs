@@ -0,0 +1,589 @@
+/*
+settings for the web_server binary, read from a toml file and then overridden from the command
+line. everything is checked by validate() so mistakes show up before we bind any sockets.
+see server.toml for an example file with every setting in it
+*/
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::log::{Level, LogFormat, Logger};
+use crate::pool::{OverflowPolicy, Scheduler};
+use crate::proxy::{self, Proxy};
+use crate::request;
+use crate::router::Router;
+use crate::server::{self, ConnectionConfig, Server};
+use crate::static_files::StaticFiles;
+
+/// The config file used when `--config` isn't given, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+pub const USAGE: &str = "\
+usage: main [options] [document root]
+
+options:
+  --config <file>              read settings from a toml file (default: server.toml if present)
+  --bind <addr>                address to listen on, may be repeated (replaces the config's list)
+  --root <dir>                 directory to serve files from
+  --workers <n>                worker threads to start with
+  --max-workers <n>            worker threads to grow to under load
+  --keep-alive-timeout <secs>  how long an idle connection is kept open
+  --shutdown-timeout <secs>    how long shutdown waits for in-flight requests
+  --header-timeout <secs>      how long a client has to send a request's headers
+  --body-timeout <secs>        how long a client has to send a request's body
+  --max-connections-per-ip <n> connections one client address may have open at once
+  --compression <true|false>   compress responses for clients that accept gzip or deflate
+  --log-format <common|json>   access and event log format
+  --log-level <level>          error, warn, info or debug
+  --log-file <file>            log to a rotating file instead of stdout
+  -h, --help                   show this message
+";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub bind: Vec<String>,              // addresses to listen on
+  pub root: PathBuf,                  // document root for static files
+  pub workers: usize,                 // threads the pool starts with
+  pub max_workers: Option<usize>,     // threads the pool may grow to
+  pub queue_capacity: Option<usize>,  // connections that may wait for a worker, unbounded if unset
+  pub overflow: OverflowPolicy,       // what to do with connections once the queue is full
+  pub scheduler: Scheduler,           // how waiting connections get to the workers
+  pub keep_alive_timeout: u64,        // seconds
+  pub max_requests: usize,            // per connection
+  pub shutdown_timeout: u64,          // seconds
+  pub header_timeout: u64,            // seconds to send the request line and headers
+  pub body_timeout: u64,              // seconds to send the body
+  pub write_timeout: u64,             // seconds a write may block on a client that isn't reading
+  pub max_header_bytes: usize,        // request line plus headers
+  pub max_body_bytes: usize,
+  pub max_connections_per_ip: Option<usize>, // unlimited if unset
+  pub metrics_path: Option<String>,   // where to serve pool stats, off if unset
+  pub compression: bool,              // gzip/deflate responses for clients that accept it
+  pub cache_control: BTreeMap<String, String>, // Cache-Control for static files, by path prefix
+  pub tls: Option<TlsSettings>,       // https listeners, off if unset
+  pub proxy: Vec<ProxySettings>,      // path prefixes forwarded to other servers
+  pub log: LogConfig,
+}
+
+/// A `[[proxy]]` section: requests under `prefix` are forwarded to `upstreams` in turn.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxySettings {
+  pub prefix: String,
+  pub upstreams: Vec<String>,    // host:port
+  #[serde(default)]
+  pub strip_prefix: bool,        // forward /api/users as /users
+  pub health_check: Option<String>, // path to check upstreams with, no checks if unset
+  #[serde(default = "default_health_interval")]
+  pub health_interval: u64,      // seconds
+  #[serde(default = "default_proxy_timeout")]
+  pub timeout: u64,              // seconds
+}
+
+fn default_health_interval() -> u64 {
+  proxy::DEFAULT_HEALTH_INTERVAL.as_secs()
+}
+
+fn default_proxy_timeout() -> u64 {
+  proxy::DEFAULT_TIMEOUT.as_secs()
+}
+
+/// The `[tls]` section. Only usable when built with the `tls` feature.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+  pub bind: Vec<String>,     // addresses to listen for https on
+  pub cert: PathBuf,         // pem certificate chain
+  pub key: PathBuf,          // pem private key
+  #[serde(default)]
+  pub redirect_http: bool,   // answer the plain http addresses with redirects to https
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+  pub format: LogFormat,
+  pub level: Level,
+  pub file: Option<PathBuf>, // stdout if unset
+  pub max_bytes: u64,        // size at which the log file is rotated
+  pub keep: usize,           // how many rotated files to keep
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      bind: vec![String::from("127.0.0.1:7878")],
+      root: PathBuf::from("public"),
+      workers: server::DEFAULT_WORKERS,
+      max_workers: None,
+      queue_capacity: None,
+      overflow: OverflowPolicy::Reject,
+      scheduler: Scheduler::Channel,
+      keep_alive_timeout: server::DEFAULT_KEEP_ALIVE_TIMEOUT.as_secs(),
+      max_requests: server::DEFAULT_MAX_REQUESTS,
+      shutdown_timeout: server::DEFAULT_SHUTDOWN_TIMEOUT.as_secs(),
+      header_timeout: server::DEFAULT_HEADER_TIMEOUT.as_secs(),
+      body_timeout: server::DEFAULT_BODY_TIMEOUT.as_secs(),
+      write_timeout: server::DEFAULT_WRITE_TIMEOUT.as_secs(),
+      max_header_bytes: request::MAX_HEADER_BYTES,
+      max_body_bytes: request::MAX_BODY_BYTES,
+      max_connections_per_ip: None,
+      metrics_path: None,
+      compression: false,
+      cache_control: BTreeMap::new(),
+      tls: None,
+      proxy: Vec::new(),
+      log: LogConfig::default(),
+    }
+  }
+}
+
+impl Default for LogConfig {
+  fn default() -> LogConfig {
+    LogConfig {
+      format: LogFormat::Common,
+      level: Level::Info,
+      file: None,
+      max_bytes: 10 * 1024 * 1024,
+      keep: 5,
+    }
+  }
+}
+
+impl Config {
+  /// Builds the config for a run of the binary from its arguments (without the program name):
+  /// the file named by `--config`, or `server.toml` if there is one, with the other arguments
+  /// applied on top, then validated.
+  pub fn from_args(args: &[String]) -> Result<Config, ConfigError> {
+    let path = match args.iter().position(|arg| arg == "--config") {
+      Some(index) => match args.get(index + 1) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => return Err(ConfigError::Args(String::from("--config needs a value"))),
+      },
+      None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Some(PathBuf::from(DEFAULT_CONFIG_FILE)),
+      None => None,
+    };
+
+    let mut config = match path {
+      Some(path) => Config::load(path)?,
+      None => Config::default(),
+    };
+    config.apply_args(args)?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Reads a config file. Settings it leaves out keep their defaults.
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    Config::from_toml(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+  }
+
+  pub fn from_toml(text: &str) -> Result<Config, toml::de::Error> {
+    toml::from_str(text)
+  }
+
+  /// Overrides settings from command line flags. `--config` is skipped over, it's handled by
+  /// `from_args`. A lone argument that isn't a flag is taken as the document root.
+  pub fn apply_args(&mut self, args: &[String]) -> Result<(), ConfigError> {
+    let mut binds = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      if !arg.starts_with('-') {
+        self.root = PathBuf::from(arg);
+        continue;
+      }
+      let value = args.next()
+        .ok_or_else(|| ConfigError::Args(format!("{} needs a value", arg)))?;
+      match arg.as_str() {
+        "--config" => {}
+        "--bind" => binds.push(value.clone()),
+        "--root" => self.root = PathBuf::from(value),
+        "--workers" => self.workers = parse_flag(arg, value)?,
+        "--max-workers" => self.max_workers = Some(parse_flag(arg, value)?),
+        "--keep-alive-timeout" => self.keep_alive_timeout = parse_flag(arg, value)?,
+        "--shutdown-timeout" => self.shutdown_timeout = parse_flag(arg, value)?,
+        "--header-timeout" => self.header_timeout = parse_flag(arg, value)?,
+        "--body-timeout" => self.body_timeout = parse_flag(arg, value)?,
+        "--max-connections-per-ip" => self.max_connections_per_ip = Some(parse_flag(arg, value)?),
+        "--compression" => self.compression = value.parse()
+          .map_err(|_| ConfigError::Args(format!("--compression expects true or false, got {:?}", value)))?,
+        "--log-format" => self.log.format = LogFormat::parse(value)
+          .ok_or_else(|| ConfigError::Args(format!("unknown log format {:?}", value)))?,
+        "--log-level" => self.log.level = Level::parse(value)
+          .ok_or_else(|| ConfigError::Args(format!("unknown log level {:?}", value)))?,
+        "--log-file" => self.log.file = Some(PathBuf::from(value)),
+        _ => return Err(ConfigError::Args(format!("unknown option {}", arg))),
+      }
+    }
+    if !binds.is_empty() { // addresses on the command line replace the file's, rather than adding to them
+      self.bind = binds;
+    }
+    Ok(())
+  }
+
+  /// Checks that the settings make sense together, without binding or opening anything.
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+    let tls_bind = self.tls.as_ref().map(|tls| &tls.bind[..]).unwrap_or(&[]);
+    if self.bind.is_empty() && tls_bind.is_empty() {
+      return invalid(String::from("at least one bind address is needed"));
+    }
+    for addr in self.bind.iter().chain(tls_bind) {
+      match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => {}
+        _ => return invalid(format!("bind address {:?} is not a valid host:port", addr)),
+      }
+    }
+    if !self.root.is_dir() {
+      return invalid(format!("document root {} is not a directory", self.root.display()));
+    }
+    if self.workers == 0 {
+      return invalid(String::from("workers must be at least 1"));
+    }
+    if let Some(max) = self.max_workers {
+      if max < self.workers {
+        return invalid(format!("max_workers ({}) is less than workers ({})", max, self.workers));
+      }
+    }
+    if self.queue_capacity == Some(0) {
+      return invalid(String::from("queue_capacity must be at least 1, leave it out for an unbounded queue"));
+    }
+    let timeouts = [self.keep_alive_timeout, self.shutdown_timeout, self.header_timeout, self.body_timeout,
+                    self.write_timeout];
+    if timeouts.contains(&0) {
+      return invalid(String::from("timeouts must be at least 1 second"));
+    }
+    if self.max_requests == 0 {
+      return invalid(String::from("max_requests must be at least 1"));
+    }
+    if self.max_header_bytes < 64 {
+      return invalid(String::from("max_header_bytes must be at least 64, or no request line would fit"));
+    }
+    if self.max_connections_per_ip == Some(0) {
+      return invalid(String::from("max_connections_per_ip must be at least 1, leave it out for no limit"));
+    }
+    if let Some(path) = &self.metrics_path {
+      if !path.starts_with('/') {
+        return invalid(format!("metrics_path {:?} must start with /", path));
+      }
+    }
+    if let Some(prefix) = self.cache_control.keys().find(|prefix| !prefix.starts_with('/')) {
+      return invalid(format!("cache_control prefix {:?} must start with /", prefix));
+    }
+    if let Some(tls) = &self.tls {
+      if !cfg!(feature = "tls") {
+        return invalid(String::from("[tls] needs web_server built with `--features tls`"));
+      }
+      if tls.bind.is_empty() {
+        return invalid(String::from("[tls] needs at least one bind address"));
+      }
+      for path in &[&tls.cert, &tls.key] {
+        if !path.is_file() {
+          return invalid(format!("tls file {} doesn't exist", path.display()));
+        }
+      }
+      if tls.redirect_http && self.bind.is_empty() {
+        return invalid(String::from("redirect_http needs plain http bind addresses to redirect from"));
+      }
+    }
+    for proxy in &self.proxy {
+      if !proxy.prefix.starts_with('/') {
+        return invalid(format!("proxy prefix {:?} must start with /", proxy.prefix));
+      }
+      if proxy.upstreams.is_empty() {
+        return invalid(format!("proxy {} needs at least one upstream", proxy.prefix));
+      }
+      let bad_upstream = proxy.upstreams.iter().find(|addr| match addr.rsplit_once(':') {
+        Some((host, port)) => host.is_empty() || port.parse::<u16>().is_err(),
+        None => true,
+      });
+      if let Some(addr) = bad_upstream {
+        return invalid(format!("proxy upstream {:?} is not a host:port", addr));
+      }
+      if proxy.health_interval == 0 || proxy.timeout == 0 {
+        return invalid(format!("proxy {} timeouts must be at least 1 second", proxy.prefix));
+      }
+    }
+    if self.log.file.is_some() && self.log.max_bytes == 0 {
+      return invalid(String::from("log max_bytes must be at least 1"));
+    }
+    Ok(())
+  }
+
+  /// Serves the document root with the configured Cache-Control headers.
+  pub fn static_files(&self) -> StaticFiles {
+    self.cache_control.iter()
+      .fold(StaticFiles::new(&self.root), |files, (prefix, value)| files.cache_control(prefix, value))
+  }
+
+  /// A Proxy for each `[[proxy]]` section, health checks already running.
+  pub fn proxies(&self) -> Vec<Proxy> {
+    self.proxy.iter().map(|settings| {
+      let proxy = Proxy::new(&settings.prefix, &settings.upstreams)
+        .strip_prefix(settings.strip_prefix)
+        .timeout(Duration::from_secs(settings.timeout));
+      match &settings.health_check {
+        Some(path) => proxy.health_check(path, Duration::from_secs(settings.health_interval)),
+        None => proxy,
+      }
+    }).collect()
+  }
+
+  /// The logger these settings describe.
+  pub fn logger(&self) -> io::Result<Logger> {
+    let logger = match &self.log.file {
+      Some(path) => Logger::file(path, self.log.max_bytes, self.log.keep)?,
+      None => Logger::stdout(),
+    };
+    Ok(logger.with_format(self.log.format).with_level(self.log.level))
+  }
+
+  /// Binds every address in the config and sets the server up to hand requests to `router`.
+  /// Loads the tls certificate too, if there is one.
+  pub fn server(&self, router: Router) -> io::Result<Server> {
+    let mut server = self.bind_all(router)?
+      .workers(self.workers)
+      .connection_config(ConnectionConfig {
+        keep_alive_timeout: Duration::from_secs(self.keep_alive_timeout),
+        max_requests: self.max_requests,
+        header_timeout: Duration::from_secs(self.header_timeout),
+        body_timeout: Duration::from_secs(self.body_timeout),
+        write_timeout: Duration::from_secs(self.write_timeout),
+        max_header_bytes: self.max_header_bytes,
+        max_body_bytes: self.max_body_bytes,
+        max_connections_per_ip: self.max_connections_per_ip,
+      })
+      .shutdown_timeout(Duration::from_secs(self.shutdown_timeout))
+      .scheduler(self.scheduler);
+    if let Some(max) = self.max_workers {
+      server = server.max_workers(max);
+    }
+    if let Some(capacity) = self.queue_capacity {
+      server = server.queue_capacity(capacity, self.overflow);
+    }
+    if let Some(path) = &self.metrics_path {
+      server = server.metrics_route(path);
+    }
+    Ok(server)
+  }
+
+  /// Every address the config listens on, with the scheme in front, for telling the user.
+  pub fn urls(&self) -> Vec<String> {
+    let tls_bind = self.tls.as_ref().map(|tls| &tls.bind[..]).unwrap_or(&[]);
+    self.bind.iter().map(|addr| format!("http://{}", addr))
+      .chain(tls_bind.iter().map(|addr| format!("https://{}", addr)))
+      .collect()
+  }
+
+  // binds the plain http addresses, then the https ones
+  fn bind_all(&self, router: Router) -> io::Result<Server> {
+    #[cfg(feature = "tls")]
+    {
+      if let Some(tls) = &self.tls {
+        return self.bind_with_tls(router, tls);
+      }
+    }
+    let first = self.bind.first().ok_or_else(|| io::Error::other("no address to bind"))?;
+    let server = Server::bind(first.as_str(), router)?;
+    self.bind[1..].iter().try_fold(server, |server, addr| server.also_bind(addr.as_str()))
+  }
+
+  #[cfg(feature = "tls")]
+  fn bind_with_tls(&self, router: Router, tls: &TlsSettings) -> io::Result<Server> {
+    let config = crate::tls::load(&tls.cert, &tls.key)?;
+    // whichever address comes first has to be bound to get a Server to add the others to
+    let (mut server, https) = match self.bind.first() {
+      Some(addr) => (Server::bind(addr.as_str(), router)?, &tls.bind[..]),
+      None => {
+        let first = tls.bind.first().ok_or_else(|| io::Error::other("no address to bind"))?;
+        (Server::bind_tls(first.as_str(), config.clone(), router)?, &tls.bind[1..])
+      }
+    };
+    for addr in self.bind.iter().skip(1) {
+      server = server.also_bind(addr.as_str())?;
+    }
+    for addr in https {
+      server = server.also_bind_tls(addr.as_str(), config.clone())?;
+    }
+    if tls.redirect_http {
+      let port = tls.bind[0].to_socket_addrs()?.next().map(|addr| addr.port()).unwrap_or(443);
+      server = server.https_redirect(port);
+    }
+    Ok(server)
+  }
+}
+
+fn parse_flag<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+  value.parse().map_err(|_| ConfigError::Args(format!("{} expects a number, got {:?}", flag, value)))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Read(PathBuf, io::Error),         // couldn't read the config file
+  Parse(PathBuf, toml::de::Error),  // the config file isn't valid toml, or has unknown settings
+  Args(String),                     // a bad command line
+  Invalid(String),                  // settings that parsed fine but don't make sense
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::Read(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+      ConfigError::Parse(path, e) => write!(f, "error in {}: {}", path.display(), e),
+      ConfigError::Args(message) => write!(f, "{}", message),
+      ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+    }
+  }
+}
+
+impl Error for ConfigError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ConfigError::Read(_, e) => Some(e),
+      ConfigError::Parse(_, e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn reads_toml() {
+    let config = Config::from_toml(r#"
+      bind = ["127.0.0.1:8080", "[::1]:8080"]
+      workers = 2
+      queue_capacity = 10
+      overflow = "drop-oldest"
+      scheduler = "work-stealing"
+
+      [cache_control]
+      "/assets/" = "public, max-age=86400"
+
+      [log]
+      format = "json"
+      level = "debug"
+    "#).unwrap();
+    assert_eq!(config.bind, ["127.0.0.1:8080", "[::1]:8080"]);
+    assert_eq!(config.workers, 2);
+    assert_eq!(config.queue_capacity, Some(10));
+    assert_eq!(config.overflow, OverflowPolicy::DropOldest);
+    assert_eq!(config.scheduler, Scheduler::WorkStealing);
+    assert_eq!(config.log.format, LogFormat::Json);
+    assert_eq!(config.log.level, Level::Debug);
+    assert_eq!(config.cache_control["/assets/"], "public, max-age=86400");
+    assert_eq!(config.keep_alive_timeout, 5); // untouched settings keep their defaults
+  }
+
+  #[test]
+  fn example_file_is_valid() {
+    let config = Config::load(DEFAULT_CONFIG_FILE).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.metrics_path.as_deref(), Some("/metrics"));
+  }
+
+  #[test]
+  fn tls_section() {
+    let config = Config::from_toml(r#"
+      [tls]
+      bind = ["127.0.0.1:7443"]
+      cert = "certs/localhost.pem"
+      key = "certs/localhost-key.pem"
+    "#).unwrap();
+    let tls = config.tls.as_ref().unwrap();
+    assert_eq!(tls.cert, PathBuf::from("certs/localhost.pem"));
+    assert!(!tls.redirect_http);
+    assert_eq!(config.urls(), ["http://127.0.0.1:7878", "https://127.0.0.1:7443"]);
+    // usable only if we were built with it
+    assert_eq!(config.validate().is_ok(), cfg!(feature = "tls"));
+
+    let missing_key = Config::from_toml("[tls]\nbind = [\"127.0.0.1:7443\"]\ncert = \"certs/localhost.pem\"");
+    assert!(missing_key.is_err());
+  }
+
+  #[test]
+  fn proxy_sections() {
+    let config = Config::from_toml(r#"
+      [[proxy]]
+      prefix = "/api"
+      upstreams = ["127.0.0.1:9001", "localhost:9002"]
+      health_check = "/health"
+
+      [[proxy]]
+      prefix = "/legacy"
+      upstreams = ["127.0.0.1:9100"]
+      strip_prefix = true
+    "#).unwrap();
+    assert_eq!(config.proxy.len(), 2);
+    assert_eq!(config.proxy[0].health_interval, 10);
+    assert!(config.proxy[1].strip_prefix);
+    assert!(config.validate().is_ok());
+
+    let no_port = Config::from_toml("[[proxy]]\nprefix = \"/api\"\nupstreams = [\"localhost\"]").unwrap();
+    assert!(matches!(no_port.validate(), Err(ConfigError::Invalid(_))));
+  }
+
+  #[test]
+  fn rejects_unknown_settings() {
+    assert!(Config::from_toml("wrokers = 4").is_err());
+    assert!(Config::from_toml("[log]\nformat = \"xml\"").is_err());
+  }
+
+  #[test]
+  fn args_override_file() {
+    let mut config = Config::from_toml("bind = [\"0.0.0.0:80\"]\nworkers = 2").unwrap();
+    config.apply_args(&args(&["--config", "ignored.toml", "--workers", "8", "--bind", "127.0.0.1:1",
+                              "--bind", "127.0.0.1:2", "--log-format", "json", "site"])).unwrap();
+    assert_eq!(config.workers, 8);
+    assert_eq!(config.bind, ["127.0.0.1:1", "127.0.0.1:2"]);
+    assert_eq!(config.log.format, LogFormat::Json);
+    assert_eq!(config.root, PathBuf::from("site"));
+  }
+
+  #[test]
+  fn bad_args() {
+    let mut config = Config::default();
+    assert!(matches!(config.apply_args(&args(&["--workers", "lots"])), Err(ConfigError::Args(_))));
+    assert!(matches!(config.apply_args(&args(&["--workers"])), Err(ConfigError::Args(_))));
+    assert!(matches!(config.apply_args(&args(&["--colour", "blue"])), Err(ConfigError::Args(_))));
+  }
+
+  #[test]
+  fn validation() {
+    assert!(Config::default().validate().is_ok()); // tests run in the crate directory, next to public/
+
+    let broken = [
+      Config { bind: vec![String::from("localhost")], ..Config::default() },
+      Config { root: PathBuf::from("no/such/dir"), ..Config::default() },
+      Config { workers: 0, ..Config::default() },
+      Config { workers: 4, max_workers: Some(2), ..Config::default() },
+      Config { queue_capacity: Some(0), ..Config::default() },
+      Config { keep_alive_timeout: 0, ..Config::default() },
+      Config { header_timeout: 0, ..Config::default() },
+      Config { max_connections_per_ip: Some(0), ..Config::default() },
+      Config { metrics_path: Some(String::from("metrics")), ..Config::default() },
+    ];
+    for config in &broken {
+      assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))), "{:?} should be invalid", config);
+    }
+  }
+}
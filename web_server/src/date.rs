@@ -0,0 +1,111 @@
+/*
+formatting and parsing the handful of date formats we need: rfc 3339 for json logs, the Common Log
+Format's own style, and the http date used by Last-Modified and If-Modified-Since. all in UTC,
+which is all any of them need
+*/
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+// splits a unix timestamp into (year, month, day, hour, minute, second) in UTC. the date part is
+// Howard Hinnant's days_from_civil algorithm run backwards, which saves pulling in a date crate
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+  let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+  let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+  (year, month, day, (rem / 3600) as u32, (rem % 3600 / 60) as u32, (rem % 60) as u32)
+}
+
+// and days_from_civil the right way round: days since 1970-01-01 for a date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let yoe = year.rem_euclid(400);
+  let mp = (month as i64 + 9) % 12;
+  let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146_097 + doe - 719_468
+}
+
+/// Formats a time like `2000-10-10T13:55:36Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+  let (year, month, day, hour, minute, second) = civil_time(time);
+  format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// Formats a time the way Common Log Format wants it, like `10/Oct/2000:13:55:36 +0000`.
+pub fn format_clf(time: SystemTime) -> String {
+  let (year, month, day, hour, minute, second) = civil_time(time);
+  format!("{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+/// Formats a time as an http date, like `Tue, 10 Oct 2000 13:55:36 GMT`.
+pub fn format_http(time: SystemTime) -> String {
+  let (year, month, day, hour, minute, second) = civil_time(time);
+  let weekday = (days_from_civil(year, month, day) + 4).rem_euclid(7); // 1970-01-01 was a thursday
+  format!("{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+          WEEKDAYS[weekday as usize], day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+/// Parses an http date in the `Tue, 10 Oct 2000 13:55:36 GMT` form every current client sends.
+/// The weekday isn't checked.
+pub fn parse_http(text: &str) -> Option<SystemTime> {
+  let (_weekday, rest) = text.trim().split_once(", ")?;
+  let parts: Vec<&str> = rest.split(' ').collect();
+  if parts.len() != 5 || parts[4] != "GMT" {
+    return None;
+  }
+  let day: u32 = parts[0].parse().ok()?;
+  let month = MONTHS.iter().position(|&month| month == parts[1])? as u32 + 1;
+  let year: i64 = parts[2].parse().ok()?;
+  // before 1970 no file we serve is going to be, and four digits is all the format has room for.
+  // anything else is a client making things up, and could overflow the arithmetic below
+  if !(1970..=9999).contains(&year) {
+    return None;
+  }
+  let time: Vec<u32> = parts[3].split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+  if time.len() != 3 || day == 0 || day > 31 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+    return None;
+  }
+
+  let secs = days_from_civil(year, month, day) * 86_400 + (time[0] * 3600 + time[1] * 60 + time[2]) as i64;
+  Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn formats() {
+    assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    assert_eq!(format_rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    assert_eq!(format_clf(UNIX_EPOCH + Duration::from_secs(1_792_367_999)), "18/Oct/2026:23:59:59 +0000");
+    assert_eq!(format_http(UNIX_EPOCH + Duration::from_secs(784_111_777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+  }
+
+  #[test]
+  fn parses_http_dates() {
+    assert_eq!(parse_http("Sun, 06 Nov 1994 08:49:37 GMT"), Some(UNIX_EPOCH + Duration::from_secs(784_111_777)));
+    let now = UNIX_EPOCH + Duration::from_secs(1_792_367_999);
+    assert_eq!(parse_http(&format_http(now)), Some(now));
+    assert_eq!(parse_http("Sunday, 06-Nov-94 08:49:37 GMT"), None); // the obsolete rfc 850 form
+    assert_eq!(parse_http("Sun, 06 Nov 1994 08:49:37 PST"), None);
+    assert_eq!(parse_http("yesterday"), None);
+    assert_eq!(parse_http("Sun, 06 Nov 9223372036854775807 08:49:37 GMT"), None); // would overflow
+    assert_eq!(parse_http("Sun, 06 Nov -1994 08:49:37 GMT"), None);
+    assert_eq!(parse_http("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+  }
+}
@@ -0,0 +1,79 @@
+/*
+a small header map for http requests and responses
+*/
+
+use std::fmt;
+
+// header names are case-insensitive, so we keep them in the order they arrived and compare them
+// with eq_ignore_ascii_case. a Vec is plenty fast for the handful of headers a request carries
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+  entries: Vec<(String, String)>,
+}
+
+impl Headers {
+  pub fn new() -> Headers {
+    Headers { entries: Vec::new() }
+  }
+
+  /// Returns the first value stored under `name`, ignoring case.
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.entries.iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Returns every value stored under `name`, in the order they were added.
+  pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    self.entries.iter()
+      .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  pub fn contains(&self, name: &str) -> bool {
+    self.get(name).is_some()
+  }
+
+  /// Adds a value without touching any existing values of the same name.
+  pub fn append(&mut self, name: &str, value: &str) {
+    self.entries.push((name.to_string(), value.to_string()));
+  }
+
+  /// Replaces all values stored under `name` with a single value.
+  pub fn set(&mut self, name: &str, value: &str) {
+    self.remove(name);
+    self.append(name, value);
+  }
+
+  pub fn remove(&mut self, name: &str) {
+    self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// True if the comma separated header `name` lists `token`, e.g. `Connection: keep-alive`.
+  pub fn has_token(&self, name: &str, token: &str) -> bool {
+    self.get_all(name)
+      .flat_map(|value| value.split(','))
+      .any(|item| item.trim().eq_ignore_ascii_case(token))
+  }
+}
+
+impl fmt::Display for Headers { // writes headers in wire format, one "name: value\r\n" per line
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (key, value) in &self.entries {
+      write!(f, "{}: {}\r\n", key, value)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,148 @@
+/*
+json request and response bodies, for handlers that speak json instead of serving files.
+
+`request.json::<T>()` checks the Content-Type and parses the body into any Deserialize type, and
+`Response::json(&value)` goes the other way. when parsing fails, JsonError knows which status to
+answer with and `into_response` builds the `{"error": "..."}` body for it
+*/
+
+use std::error::Error;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use crate::request::Request;
+use crate::response::Response;
+
+pub const CONTENT_TYPE: &str = "application/json";
+
+/// Why a request body couldn't be read as json.
+#[derive(Debug)]
+pub enum JsonError {
+  WrongContentType(Option<String>), // what the client said it sent instead, if anything
+  Malformed(serde_json::Error),     // not json at all, or cut off
+  Invalid(serde_json::Error),       // json, but not the shape we asked for
+}
+
+impl JsonError {
+  /// The status code a handler should answer this error with.
+  pub fn status_code(&self) -> u16 {
+    match self {
+      JsonError::WrongContentType(_) => 415,
+      JsonError::Malformed(_) => 400,
+      JsonError::Invalid(_) => 422,
+    }
+  }
+
+  /// A json response describing the error, with the matching status.
+  pub fn into_response(self) -> Response {
+    error(self.status_code(), &self.to_string())
+  }
+}
+
+impl fmt::Display for JsonError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      JsonError::WrongContentType(Some(content_type)) =>
+        write!(f, "expected an {} body, got {}", CONTENT_TYPE, content_type),
+      JsonError::WrongContentType(None) => write!(f, "expected an {} body", CONTENT_TYPE),
+      JsonError::Malformed(e) => write!(f, "malformed json: {}", e),
+      JsonError::Invalid(e) => write!(f, "invalid json: {}", e),
+    }
+  }
+}
+
+impl Error for JsonError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      JsonError::Malformed(e) | JsonError::Invalid(e) => Some(e),
+      JsonError::WrongContentType(_) => None,
+    }
+  }
+}
+
+impl From<serde_json::Error> for JsonError {
+  fn from(error: serde_json::Error) -> JsonError {
+    match error.classify() {
+      Category::Data => JsonError::Invalid(error),
+      _ => JsonError::Malformed(error),
+    }
+  }
+}
+
+/// A json `{"error": message}` response with the given status.
+pub fn error(status: u16, message: &str) -> Response {
+  Response::json(&serde_json::json!({ "error": message })).with_status(status)
+}
+
+// application/json, or one of its relatives like application/problem+json. parameters such as
+// charset don't matter, json is always utf-8
+fn is_json(content_type: &str) -> bool {
+  let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+  media_type == CONTENT_TYPE || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+pub(crate) fn parse<T: DeserializeOwned>(request: &Request) -> Result<T, JsonError> {
+  match request.header("Content-Type") {
+    Some(content_type) if is_json(content_type) => Ok(serde_json::from_slice(&request.body)?),
+    other => Err(JsonError::WrongContentType(other.map(String::from))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde::{Deserialize, Serialize};
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct Point {
+    x: i32,
+    y: i32,
+  }
+
+  fn post(content_type: Option<&str>, body: &str) -> Request {
+    let mut raw = String::from("POST /points HTTP/1.1\r\n");
+    if let Some(content_type) = content_type {
+      raw += &format!("Content-Type: {}\r\n", content_type);
+    }
+    raw += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    Request::read_from(&mut raw.as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn parses_bodies() {
+    let request = post(Some("application/json; charset=utf-8"), r#"{"x": 1, "y": -2}"#);
+    assert_eq!(request.json::<Point>().unwrap(), Point { x: 1, y: -2 });
+
+    let problem = post(Some("application/merge-patch+json"), r#"{"x": 3, "y": 4}"#);
+    assert!(problem.json::<Point>().is_ok());
+  }
+
+  #[test]
+  fn errors() {
+    let status = |request: Request| request.json::<Point>().unwrap_err().status_code();
+    assert_eq!(status(post(None, r#"{"x": 1, "y": 2}"#)), 415);
+    assert_eq!(status(post(Some("text/plain"), r#"{"x": 1, "y": 2}"#)), 415);
+    assert_eq!(status(post(Some("application/json"), r#"{"x": 1,"#)), 400);
+    assert_eq!(status(post(Some("application/json"), r#"{"x": "one", "y": 2}"#)), 422);
+    assert_eq!(status(post(Some("application/json"), r#"{"x": 1}"#)), 422);
+
+    let response = post(Some("application/json"), "nope").json::<Point>().unwrap_err().into_response();
+    assert_eq!(response.status, 400);
+    let body: serde_json::Value = serde_json::from_slice(response.body.as_bytes().unwrap()).unwrap();
+    assert!(body["error"].as_str().unwrap().starts_with("malformed json"));
+  }
+
+  #[test]
+  fn responses() {
+    let response = Response::json(&Point { x: 5, y: 6 });
+    assert_eq!(response.status, 200);
+    assert_eq!(response.headers.get("Content-Type"), Some(CONTENT_TYPE));
+    assert_eq!(response.body.as_bytes().unwrap(), br#"{"x":5,"y":6}"#);
+
+    let response = Response::json(&vec![Point { x: 1, y: 1 }]).with_status(201);
+    assert_eq!(response.status, 201);
+    assert_eq!(response.body.as_bytes().unwrap(), br#"[{"x":1,"y":1}]"#);
+  }
+}
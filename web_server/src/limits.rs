@@ -0,0 +1,184 @@
+/*
+protection against clients that tie up workers without doing anything useful, the classic being
+slowloris: open a connection, then send a request one byte every few seconds so no single read
+ever times out.
+
+DeadlineStream turns a per-read timeout into a deadline for a whole phase (waiting for a request,
+reading its headers, reading its body). PeerLimiter caps how many connections one address may have
+open at once, so a single client can't hold every worker
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+/// A socket whose reads fail with `TimedOut` once a deadline has passed, however slowly the
+/// bytes trickle in before then. Writes go straight through.
+pub struct DeadlineStream {
+  stream: TcpStream,
+  deadline: Option<Instant>,
+}
+
+impl DeadlineStream {
+  pub fn new(stream: TcpStream) -> DeadlineStream {
+    DeadlineStream { stream, deadline: None }
+  }
+
+  pub fn get_ref(&self) -> &TcpStream {
+    &self.stream
+  }
+
+  pub fn get_mut(&mut self) -> &mut TcpStream {
+    &mut self.stream
+  }
+
+  /// Reads after `deadline` fail. `None` lets them block for as long as it takes.
+  pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.deadline = deadline;
+  }
+}
+
+impl Read for DeadlineStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if let Some(deadline) = self.deadline {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.as_nanos() == 0 {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"));
+      }
+      // the socket timeout only covers one read, so shrink it to what's left each time
+      self.stream.set_read_timeout(Some(remaining))?;
+    } else {
+      self.stream.set_read_timeout(None)?;
+    }
+    self.stream.read(buf)
+  }
+}
+
+impl Write for DeadlineStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.stream.write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.stream.flush()
+  }
+}
+
+/// Counts open connections per client address and refuses new ones past a limit.
+#[derive(Debug)]
+pub struct PeerLimiter {
+  max: Option<usize>, // None counts nothing and lets everyone in
+  open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PeerLimiter {
+  pub fn new(max: Option<usize>) -> Arc<PeerLimiter> {
+    Arc::new(PeerLimiter { max, open: Mutex::new(HashMap::new()) })
+  }
+
+  /// Takes one of `ip`'s connection slots, or returns `None` if it already has its share. The
+  /// slot is given back when the permit is dropped.
+  pub fn acquire(self: &Arc<PeerLimiter>, ip: IpAddr) -> Option<PeerPermit> {
+    let max = match self.max {
+      Some(max) => max,
+      None => return Some(PeerPermit { limiter: None, ip }),
+    };
+    let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+    let count = open.entry(ip).or_insert(0);
+    if *count >= max {
+      return None;
+    }
+    *count += 1;
+    Some(PeerPermit { limiter: Some(Arc::clone(self)), ip })
+  }
+
+  /// How many connections `ip` has open right now.
+  pub fn open_connections(&self, ip: IpAddr) -> usize {
+    *self.open.lock().unwrap_or_else(PoisonError::into_inner).get(&ip).unwrap_or(&0)
+  }
+}
+
+/// One connection's claim on its address's share. Hold it for as long as the connection is open.
+#[derive(Debug)]
+pub struct PeerPermit {
+  limiter: Option<Arc<PeerLimiter>>,
+  ip: IpAddr,
+}
+
+impl Drop for PeerPermit {
+  fn drop(&mut self) {
+    if let Some(limiter) = &self.limiter {
+      let mut open = limiter.open.lock().unwrap_or_else(PoisonError::into_inner);
+      if let Some(count) = open.get_mut(&self.ip) {
+        *count -= 1;
+        if *count == 0 {
+          open.remove(&self.ip); // don't keep an entry for every address we've ever seen
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::{TcpListener, TcpStream};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn limits_each_address() {
+    let limiter = PeerLimiter::new(Some(2));
+    let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+    let first = limiter.acquire(a).unwrap();
+    let _second = limiter.acquire(a).unwrap();
+    assert!(limiter.acquire(a).is_none());
+    assert!(limiter.acquire(b).is_some()); // other addresses have their own share
+
+    drop(first);
+    assert_eq!(limiter.open_connections(a), 1);
+    assert!(limiter.acquire(a).is_some());
+  }
+
+  #[test]
+  fn unlimited() {
+    let limiter = PeerLimiter::new(None);
+    let ip = "10.0.0.1".parse().unwrap();
+    let permits: Vec<_> = (0..100).map(|_| limiter.acquire(ip).unwrap()).collect();
+    assert_eq!(permits.len(), 100);
+    assert_eq!(limiter.open_connections(ip), 0);
+  }
+
+  #[test]
+  fn deadline_beats_a_trickle() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+      let mut stream = TcpStream::connect(addr).unwrap();
+      for _ in 0..20 { // a byte every 20ms would never trip a 100ms timeout on its own
+        if stream.write_all(b"a").is_err() {
+          break;
+        }
+        thread::sleep(Duration::from_millis(20));
+      }
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut reader = DeadlineStream::new(stream);
+    reader.set_deadline(Some(Instant::now() + Duration::from_millis(100)));
+    let started = Instant::now();
+    let mut buf = [0; 1];
+    let error = loop {
+      if let Err(e) = reader.read(&mut buf) {
+        break e;
+      }
+    };
+    assert!(matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock));
+    assert!(started.elapsed() < Duration::from_millis(300));
+    client.join().unwrap();
+  }
+}
@@ -0,0 +1,352 @@
+/*
+levelled log events and per-request access logging.
+
+there is one global Logger, so the thread pool, the server and the application all write to the
+same place without passing a handle around. install() replaces the default, which writes Info and
+above to stdout in the plain text format
+*/
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::date::{format_clf, format_rfc3339};
+
+static LOGGER: RwLock<Option<Arc<Logger>>> = RwLock::new(None);
+
+/// Logs a message through the global logger, e.g. `log_event!(Level::Info, "listening on {}", addr)`.
+/// The message is only formatted if `level` is enabled.
+#[macro_export]
+macro_rules! log_event {
+  ($level:expr, $($arg:tt)*) => {
+    $crate::log::event($level, format_args!($($arg)*))
+  };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+  Error,
+  #[serde(alias = "warning")]
+  Warn,
+  Info,
+  Debug,
+}
+
+impl Level {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+    }
+  }
+
+  /// Parses a level name like "info", ignoring case.
+  pub fn parse(name: &str) -> Option<Level> {
+    match name.to_ascii_lowercase().as_str() {
+      "error" => Some(Level::Error),
+      "warn" | "warning" => Some(Level::Warn),
+      "info" => Some(Level::Info),
+      "debug" => Some(Level::Debug),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+  Common, // Common Log Format for requests, "time level message" for everything else
+  Json,   // one json object per line
+}
+
+impl LogFormat {
+  /// Parses "common" or "json", ignoring case.
+  pub fn parse(name: &str) -> Option<LogFormat> {
+    match name.to_ascii_lowercase().as_str() {
+      "common" => Some(LogFormat::Common),
+      "json" => Some(LogFormat::Json),
+      _ => None,
+    }
+  }
+}
+
+/// One served request, as recorded in the access log.
+#[derive(Debug, Clone)]
+pub struct AccessEntry<'a> {
+  pub time: SystemTime, // when the request arrived
+  pub peer: Option<SocketAddr>,
+  pub method: &'a str,
+  pub target: &'a str,
+  pub version: &'a str,
+  pub status: u16,
+  pub bytes: u64, // size of the response body
+  pub duration: Duration,
+}
+
+pub struct Logger {
+  level: Level,
+  format: LogFormat,
+  sink: Mutex<Box<dyn Write + Send>>, // one line is written at a time so lines never interleave
+}
+
+impl Logger {
+  /// A logger writing to stdout.
+  pub fn stdout() -> Logger {
+    Logger::to_writer(io::stdout())
+  }
+
+  /// A logger writing to the file at `path`. Once the file passes `max_bytes` it is renamed to
+  /// `path.1` (and any older `path.1` to `path.2`, and so on) keeping at most `keep` old files.
+  pub fn file<P: AsRef<Path>>(path: P, max_bytes: u64, keep: usize) -> io::Result<Logger> {
+    Ok(Logger::to_writer(RotatingFile::open(path.as_ref(), max_bytes, keep)?))
+  }
+
+  /// A logger writing to anything that implements Write.
+  pub fn to_writer<W: Write + Send + 'static>(writer: W) -> Logger {
+    Logger { level: Level::Info, format: LogFormat::Common, sink: Mutex::new(Box::new(writer)) }
+  }
+
+  /// Only log events at `level` or more severe. Access log lines are written at Info.
+  pub fn with_level(mut self, level: Level) -> Logger {
+    self.level = level;
+    self
+  }
+
+  pub fn with_format(mut self, format: LogFormat) -> Logger {
+    self.format = format;
+    self
+  }
+
+  /// Makes this the logger everything in the process writes to.
+  pub fn install(self) {
+    *LOGGER.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(self));
+  }
+
+  pub fn enabled(&self, level: Level) -> bool {
+    level <= self.level
+  }
+
+  pub fn event(&self, level: Level, message: fmt::Arguments) {
+    if !self.enabled(level) {
+      return;
+    }
+    let time = format_rfc3339(SystemTime::now());
+    let line = match self.format {
+      LogFormat::Common => format!("{} {:5} {}\n", time, level.as_str(), message),
+      LogFormat::Json => format!("{{\"time\":\"{}\",\"level\":\"{}\",\"message\":{}}}\n",
+                                 time, level.as_str(), json_string(&message.to_string())),
+    };
+    self.write_line(&line);
+  }
+
+  pub fn access(&self, entry: &AccessEntry) {
+    if !self.enabled(Level::Info) {
+      return;
+    }
+    let peer = entry.peer.map(|peer| peer.ip().to_string()).unwrap_or_else(|| String::from("-"));
+    let line = match self.format {
+      // host ident authuser [date] "request line" status bytes, plus how long it took in microseconds
+      LogFormat::Common => format!("{} - - [{}] \"{} {} {}\" {} {} {}\n",
+                                   peer, format_clf(entry.time), entry.method, entry.target, entry.version,
+                                   entry.status, entry.bytes, entry.duration.as_micros()),
+      LogFormat::Json => format!(
+        "{{\"time\":\"{}\",\"level\":\"info\",\"peer\":{},\"method\":{},\"path\":{},\"version\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3}}}\n",
+        format_rfc3339(entry.time), json_string(&peer), json_string(entry.method), json_string(entry.target),
+        json_string(entry.version), entry.status, entry.bytes, entry.duration.as_secs_f64() * 1000.0),
+    };
+    self.write_line(&line);
+  }
+
+  fn write_line(&self, line: &str) {
+    let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+    // there's nowhere sensible to report a failure to log, so drop the line
+    let _ = sink.write_all(line.as_bytes()).and_then(|_| sink.flush());
+  }
+}
+
+/// The global logger, creating the default stdout one if nothing was installed.
+pub fn logger() -> Arc<Logger> {
+  if let Some(logger) = LOGGER.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+    return Arc::clone(logger);
+  }
+  let mut installed = LOGGER.write().unwrap_or_else(PoisonError::into_inner);
+  Arc::clone(installed.get_or_insert_with(|| Arc::new(Logger::stdout())))
+}
+
+/// Logs an event through the global logger. Usually called through `log_event!`.
+pub fn event(level: Level, message: fmt::Arguments) {
+  logger().event(level, message);
+}
+
+/// Writes an access log line through the global logger.
+pub fn access(entry: &AccessEntry) {
+  logger().access(entry);
+}
+
+// a log file that starts over in a fresh file once it gets too big
+struct RotatingFile {
+  path: PathBuf,
+  file: File,
+  written: u64,
+  max_bytes: u64,
+  keep: usize,
+}
+
+impl RotatingFile {
+  fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok(RotatingFile { path: path.to_path_buf(), file, written, max_bytes, keep })
+  }
+
+  fn rotate(&mut self) -> io::Result<()> {
+    // shuffle path.(n-1) -> path.n down to path -> path.1, the oldest falls off the end
+    for n in (1..=self.keep).rev() {
+      let from = if n == 1 { self.path.clone() } else { numbered(&self.path, n - 1) };
+      if from.exists() {
+        fs::rename(&from, numbered(&self.path, n))?;
+      }
+    }
+    self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+    self.written = 0;
+    Ok(())
+  }
+}
+
+impl Write for RotatingFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+      self.rotate()?;
+    }
+    let written = self.file.write(buf)?;
+    self.written += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(format!(".{}", n));
+  PathBuf::from(name)
+}
+
+/// Quotes and escapes `value` as a json string.
+pub fn json_string(value: &str) -> String {
+  let mut out = String::with_capacity(value.len() + 2);
+  out.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::UNIX_EPOCH;
+
+  // a Write that tests can read back from after handing it to a Logger
+  #[derive(Clone, Default)]
+  struct Captured(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Captured {
+    fn text(&self) -> String {
+      String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+  }
+
+  fn entry() -> AccessEntry<'static> {
+    AccessEntry {
+      time: UNIX_EPOCH + Duration::from_secs(971_186_136), // 10 Oct 2000 13:55:36
+      peer: Some("127.0.0.1:50000".parse().unwrap()),
+      method: "GET",
+      target: "/apache_pb.gif",
+      version: "HTTP/1.0",
+      status: 200,
+      bytes: 2326,
+      duration: Duration::from_micros(1500),
+    }
+  }
+
+  #[test]
+  fn common_log_format() {
+    let captured = Captured::default();
+    Logger::to_writer(captured.clone()).access(&entry());
+    assert_eq!(captured.text(),
+               "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 1500\n");
+  }
+
+  #[test]
+  fn json_format() {
+    let captured = Captured::default();
+    let logger = Logger::to_writer(captured.clone()).with_format(LogFormat::Json);
+    logger.access(&entry());
+    logger.event(Level::Warn, format_args!("said \"hi\""));
+    let text = captured.text();
+    assert!(text.starts_with("{\"time\":\"2000-10-10T13:55:36Z\",\"level\":\"info\",\"peer\":\"127.0.0.1\""));
+    assert!(text.contains("\"status\":200,\"bytes\":2326,\"duration_ms\":1.500}\n"));
+    assert!(text.contains("\"level\":\"warn\",\"message\":\"said \\\"hi\\\"\"}\n"));
+  }
+
+  #[test]
+  fn levels_filter_events() {
+    let captured = Captured::default();
+    let logger = Logger::to_writer(captured.clone()).with_level(Level::Warn);
+    logger.event(Level::Info, format_args!("too chatty"));
+    logger.event(Level::Error, format_args!("important"));
+    let text = captured.text();
+    assert!(!text.contains("too chatty"));
+    assert!(text.contains("error important"));
+  }
+
+
+  #[test]
+  fn rotates_files() {
+    let dir = std::env::temp_dir().join(format!("web_server_log_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+    for line in &["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+      file.write_all(line.as_bytes()).unwrap();
+    }
+    assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+    assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "cccccccc\n");
+    assert_eq!(fs::read_to_string(numbered(&path, 2)).unwrap(), "bbbbbbbb\n");
+    assert!(!numbered(&path, 3).exists()); // only two old files are kept
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,88 @@
+/*
+rendering ThreadPool stats in the Prometheus text exposition format, so the server can answer
+scrapes on a /metrics route
+*/
+
+use std::fmt::Write;
+
+use crate::pool::{Histogram, PoolStats, LATENCY_BUCKETS};
+use crate::response::Response;
+
+/// Content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders `stats` as Prometheus metrics, each name starting with `web_server_pool_`.
+pub fn render_prometheus(stats: &PoolStats) -> String {
+  let mut out = String::new();
+
+  metric(&mut out, "workers", "Threads currently in the pool.", "gauge", stats.workers as u64);
+  metric(&mut out, "active_workers", "Threads currently running a job.", "gauge", stats.active_workers as u64);
+  metric(&mut out, "queued_jobs", "Jobs waiting for a free worker.", "gauge", stats.queued_jobs as u64);
+  metric(&mut out, "jobs_completed_total", "Jobs that ran to completion.", "counter", stats.completed_jobs);
+  metric(&mut out, "jobs_panicked_total", "Jobs that panicked.", "counter", stats.panicked_jobs);
+  metric(&mut out, "jobs_dropped_total", "Jobs dropped from a full queue.", "counter", stats.dropped_jobs);
+  histogram(&mut out, "job_wait_seconds", "Time jobs spent waiting in the queue.", &stats.queue_wait);
+  histogram(&mut out, "job_run_seconds", "Time jobs spent running.", &stats.run_time);
+
+  out
+}
+
+/// A 200 response carrying `stats` in the Prometheus format.
+pub fn response(stats: &PoolStats) -> Response {
+  Response::new(200)
+    .with_header("Content-Type", CONTENT_TYPE)
+    .with_body(render_prometheus(stats))
+}
+
+// writing to a String can't fail, hence all the unwraps below
+
+// a metric with a single value, `kind` being "gauge" or "counter"
+fn metric(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+  writeln!(out, "# HELP web_server_pool_{} {}", name, help).unwrap();
+  writeln!(out, "# TYPE web_server_pool_{} {}", name, kind).unwrap();
+  writeln!(out, "web_server_pool_{} {}", name, value).unwrap();
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+  writeln!(out, "# HELP web_server_pool_{} {}", name, help).unwrap();
+  writeln!(out, "# TYPE web_server_pool_{} histogram", name).unwrap();
+
+  let cumulative = histogram.cumulative();
+  for (bound, count) in LATENCY_BUCKETS.iter().zip(&cumulative) {
+    writeln!(out, "web_server_pool_{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+  }
+  writeln!(out, "web_server_pool_{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count()).unwrap();
+  writeln!(out, "web_server_pool_{}_sum {}", name, histogram.sum.as_secs_f64()).unwrap();
+  writeln!(out, "web_server_pool_{}_count {}", name, histogram.count()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn renders_counters_and_histograms() {
+    let mut run_time = vec![0; LATENCY_BUCKETS.len() + 1];
+    run_time[0] = 2; // two fast jobs
+    run_time[LATENCY_BUCKETS.len()] = 1; // and one really slow one
+    let stats = PoolStats {
+      workers: 4,
+      active_workers: 1,
+      queued_jobs: 0,
+      completed_jobs: 3,
+      panicked_jobs: 0,
+      dropped_jobs: 0,
+      queue_wait: Histogram { counts: vec![0; LATENCY_BUCKETS.len() + 1], sum: Duration::from_secs(0) },
+      run_time: Histogram { counts: run_time, sum: Duration::from_millis(20500) },
+    };
+
+    let text = render_prometheus(&stats);
+    assert!(text.contains("# TYPE web_server_pool_workers gauge\nweb_server_pool_workers 4\n"));
+    assert!(text.contains("web_server_pool_jobs_completed_total 3\n"));
+    assert!(text.contains("web_server_pool_job_run_seconds_bucket{le=\"0.0001\"} 2\n"));
+    assert!(text.contains("web_server_pool_job_run_seconds_bucket{le=\"10\"} 2\n"));
+    assert!(text.contains("web_server_pool_job_run_seconds_bucket{le=\"+Inf\"} 3\n"));
+    assert!(text.contains("web_server_pool_job_run_seconds_sum 20.5\n"));
+  }
+}
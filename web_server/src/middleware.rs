@@ -0,0 +1,255 @@
+/*
+middleware wraps request handling with code that runs before and after the handler, so things like
+auth, cors or extra headers don't each need their own hook in the server.
+
+a Chain runs the before hooks in the order the middleware was added, then the handler, then the
+after hooks in reverse order. a before hook can answer the request itself, in which case the
+handler and the middleware after it are skipped, but the after hooks of everything that already
+ran (including the one that answered) still run:
+
+  request -> a.before -> b.before -> handler -> b.after -> a.after -> response
+  request -> a.before -> b.before (answers) -> b.after -> a.after -> response
+*/
+
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::Request;
+use crate::response::Response;
+
+pub trait Middleware: Send + Sync {
+  /// Runs before the handler. Returning a response skips the handler and any later middleware
+  /// and sends that response instead.
+  fn before(&self, _request: &mut Request) -> Option<Response> {
+    None
+  }
+
+  /// Runs after the handler, or after a before hook answered the request. Once the handler has
+  /// had the request its body is gone, so `request.body` is only there in the second case.
+  fn after(&self, _request: &Request, response: Response) -> Response {
+    response
+  }
+}
+
+/// Middleware in the order it runs.
+#[derive(Default)]
+pub struct Chain {
+  middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Chain {
+  pub fn new() -> Chain {
+    Chain::default()
+  }
+
+  /// Adds `middleware` to the end of the chain, so its before hook runs after all of the
+  /// existing ones and its after hook runs before them.
+  pub fn add<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Chain {
+    self.middleware.push(Box::new(middleware));
+    self
+  }
+
+  pub fn len(&self) -> usize {
+    self.middleware.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.middleware.is_empty()
+  }
+
+  /// Runs `request` through the chain, calling `handler` unless some middleware answers first.
+  pub fn handle<F>(&self, mut request: Request, handler: F) -> Response
+    where
+      F: FnOnce(Request) -> Response,
+    {
+      let mut ran = 0; // how many before hooks ran, and so how many after hooks are owed
+      let mut answered = None;
+      for middleware in &self.middleware {
+        ran += 1;
+        answered = middleware.before(&mut request);
+        if answered.is_some() {
+          break;
+        }
+      }
+
+      // the handler takes the request, but the after hooks still want to look at it. they get a
+      // copy of everything but the body, which can be megabytes nobody will read again
+      let (request, response) = match answered {
+        Some(response) => (request, response),
+        None if self.middleware.is_empty() => return handler(request),
+        None => {
+          let body = mem::take(&mut request.body);
+          let head = request.clone();
+          request.body = body;
+          (head, handler(request))
+        }
+      };
+
+      self.middleware[..ran].iter().rev()
+        .fold(response, |response, middleware| middleware.after(&request, response))
+    }
+}
+
+/// Gives each request an id in the `X-Request-Id` header, keeping one the client (or a proxy in
+/// front of us) already sent, and echoes it back on the response so the two can be matched up.
+pub struct RequestId {
+  prefix: String,
+  next: AtomicU64,
+}
+
+/// The header RequestId reads and writes.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+impl RequestId {
+  pub fn new() -> RequestId {
+    // the startup time keeps ids from repeating across restarts, the counter within a run
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    RequestId { prefix: format!("{:x}", started), next: AtomicU64::new(1) }
+  }
+}
+
+impl Default for RequestId {
+  fn default() -> RequestId {
+    RequestId::new()
+  }
+}
+
+impl Middleware for RequestId {
+  fn before(&self, request: &mut Request) -> Option<Response> {
+    if !request.headers.contains(REQUEST_ID_HEADER) {
+      let id = format!("{}-{}", self.prefix, self.next.fetch_add(1, Ordering::Relaxed));
+      request.headers.set(REQUEST_ID_HEADER, &id);
+    }
+    None
+  }
+
+  fn after(&self, request: &Request, response: Response) -> Response {
+    match request.header(REQUEST_ID_HEADER) {
+      Some(id) => response.with_header(REQUEST_ID_HEADER, id),
+      None => response,
+    }
+  }
+}
+
+/// Adds an `X-Response-Time` header saying how long we spent on the request, in milliseconds,
+/// counting from when it had been read off of the connection.
+#[derive(Debug, Default)]
+pub struct Timing;
+
+/// The header Timing writes.
+pub const TIMING_HEADER: &str = "X-Response-Time";
+
+impl Middleware for Timing {
+  fn after(&self, request: &Request, response: Response) -> Response {
+    let elapsed = request.received.elapsed();
+    response.with_header(TIMING_HEADER, &format!("{:.3}ms", elapsed.as_secs_f64() * 1000.0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  fn request() -> Request {
+    Request::read_from(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap()
+  }
+
+  // records the order hooks run in, and answers the request itself if told to
+  struct Trace {
+    name: &'static str,
+    answer: bool,
+    log: Arc<Mutex<Vec<String>>>,
+  }
+
+  impl Middleware for Trace {
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+      self.log.lock().unwrap().push(format!("{} before", self.name));
+      if self.answer {
+        Some(Response::new(401))
+      } else {
+        None
+      }
+    }
+
+    fn after(&self, _request: &Request, response: Response) -> Response {
+      self.log.lock().unwrap().push(format!("{} after", self.name));
+      response
+    }
+  }
+
+  fn traced_chain(answer_in_b: bool) -> (Chain, Arc<Mutex<Vec<String>>>) {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut chain = Chain::new();
+    for &(name, answer) in &[("a", false), ("b", answer_in_b), ("c", false)] {
+      chain.add(Trace { name, answer, log: Arc::clone(&log) });
+    }
+    (chain, log)
+  }
+
+  #[test]
+  fn runs_in_order() {
+    let (chain, log) = traced_chain(false);
+    let handler_log = Arc::clone(&log);
+    let response = chain.handle(request(), |_| {
+      handler_log.lock().unwrap().push(String::from("handler"));
+      Response::new(200)
+    });
+    assert_eq!(response.status, 200);
+    assert_eq!(*log.lock().unwrap(),
+               ["a before", "b before", "c before", "handler", "c after", "b after", "a after"]);
+  }
+
+  #[test]
+  fn before_can_answer() {
+    let (chain, log) = traced_chain(true);
+    let response = chain.handle(request(), |_| panic!("handler should be skipped"));
+    assert_eq!(response.status, 401);
+    assert_eq!(*log.lock().unwrap(), ["a before", "b before", "b after", "a after"]);
+  }
+
+  // hands the after hook's view of the request back in a header
+  struct BodySize;
+
+  impl Middleware for BodySize {
+    fn after(&self, request: &Request, response: Response) -> Response {
+      response.with_header("X-Body-Size", &request.body.len().to_string())
+    }
+  }
+
+  #[test]
+  fn only_the_handler_gets_the_body() {
+    let mut chain = Chain::new();
+    chain.add(BodySize);
+    let posted = Request::read_from(&mut &b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"[..]).unwrap();
+    let response = chain.handle(posted, |req| Response::text(String::from_utf8(req.body).unwrap()));
+    assert_eq!(response.body.as_bytes(), Some(&b"hello"[..]));
+    assert_eq!(response.headers.get("X-Body-Size"), Some("0"));
+  }
+
+  #[test]
+  fn request_ids() {
+    let mut chain = Chain::new();
+    chain.add(RequestId::new());
+
+    let first = chain.handle(request(), |req| Response::text(req.header(REQUEST_ID_HEADER).unwrap().to_string()));
+    let id = first.headers.get(REQUEST_ID_HEADER).unwrap();
+    assert_eq!(first.body.as_bytes(), Some(id.as_bytes())); // the handler saw the same id the client gets back
+    let second = chain.handle(request(), |_| Response::new(200));
+    assert_ne!(second.headers.get(REQUEST_ID_HEADER), Some(id));
+
+    let mut given = request();
+    given.headers.set(REQUEST_ID_HEADER, "from-upstream");
+    let response = chain.handle(given, |_| Response::new(200));
+    assert_eq!(response.headers.get(REQUEST_ID_HEADER), Some("from-upstream"));
+  }
+
+  #[test]
+  fn timing_header() {
+    let mut chain = Chain::new();
+    chain.add(Timing);
+    let response = chain.handle(request(), |_| Response::new(200));
+    assert!(response.headers.get(TIMING_HEADER).unwrap().ends_with("ms"));
+  }
+}
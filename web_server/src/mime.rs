@@ -0,0 +1,56 @@
+/*
+guessing a Content-Type from a file extension
+*/
+
+use std::path::Path;
+
+/// The content type used when we don't recognise an extension.
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Returns the MIME type for `path` based on its extension.
+pub fn from_path(path: &Path) -> &'static str {
+  match path.extension().and_then(|ext| ext.to_str()) {
+    Some(ext) => from_extension(ext),
+    None => DEFAULT_MIME_TYPE,
+  }
+}
+
+pub fn from_extension(extension: &str) -> &'static str {
+  match extension.to_ascii_lowercase().as_str() {
+    "html" | "htm" => "text/html; charset=utf-8",
+    "css" => "text/css; charset=utf-8",
+    "js" | "mjs" => "text/javascript; charset=utf-8",
+    "json" => "application/json",
+    "txt" => "text/plain; charset=utf-8",
+    "csv" => "text/csv; charset=utf-8",
+    "xml" => "application/xml",
+    "wasm" => "application/wasm",
+    "pdf" => "application/pdf",
+    "zip" => "application/zip",
+    "gz" => "application/gzip",
+    "png" => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "gif" => "image/gif",
+    "svg" => "image/svg+xml",
+    "ico" => "image/x-icon",
+    "webp" => "image/webp",
+    "mp3" => "audio/mpeg",
+    "mp4" => "video/mp4",
+    "webm" => "video/webm",
+    "woff" => "font/woff",
+    "woff2" => "font/woff2",
+    "ttf" => "font/ttf",
+    _ => DEFAULT_MIME_TYPE,
+  }
+}
+
+/// Whether content of this type is already compressed, so compressing it again would only
+/// waste time. `content_type` may include parameters like `; charset=utf-8`.
+pub fn is_compressed(content_type: &str) -> bool {
+  let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+  match essence.as_str() {
+    "image/svg+xml" | "image/x-icon" => false, // text and bitmaps that do squash down
+    "application/zip" | "application/gzip" | "application/x-gzip" | "font/woff" | "font/woff2" => true,
+    _ => essence.starts_with("image/") || essence.starts_with("audio/") || essence.starts_with("video/"),
+  }
+}
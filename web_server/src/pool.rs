@@ -0,0 +1,933 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+mod handle;
+mod scope;
+mod stats;
+mod stealing;
+
+pub use handle::{JobHandle, JoinError};
+pub use scope::Scope;
+pub use stats::{Histogram, PoolMonitor, PoolStats, LATENCY_BUCKETS};
+use stats::Metrics;
+use stealing::Deques;
+use crate::log::Level;
+
+pub struct ThreadPool {
+  sender: JobSender,
+  shared: Arc<Shared>,
+  terminated: bool,
+}
+
+/// What `execute` does when a bounded job queue is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+  Block,      // wait until a worker frees up a spot
+  Reject,     // return ExecuteError::QueueFull straight away
+  DropOldest, // throw away the job that has waited longest to make room
+}
+
+/// How queued jobs find their way to the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheduler {
+  #[default]
+  Channel,      // one queue shared by every worker, which take turns locking it
+  WorkStealing, // a queue per worker, idle workers steal from busy ones. see pool/stealing.rs
+}
+
+// the unbounded queue is a plain channel. a bounded one is a sync_channel, which can also
+// tell us when it's full instead of blocking. the work-stealing deques can do either
+enum JobSender {
+  Unbounded(mpsc::Sender<Message>),
+  Bounded(mpsc::SyncSender<Message>, OverflowPolicy),
+  Stealing(Arc<Deques>, OverflowPolicy), // the policy only matters if the deques have a capacity
+}
+
+// where the workers take jobs from, the other end of the JobSender
+enum Queue {
+  Channel(Mutex<mpsc::Receiver<Message>>),
+  Stealing(Arc<Deques>),
+}
+
+// state every worker thread has a reference to
+struct Shared {
+  queue: Queue,
+  // workers live here rather than in ThreadPool so idle ones can remove themselves
+  workers: Mutex<Vec<Worker>>,
+  next_id: AtomicUsize,
+  min_workers: usize,
+  max_workers: usize,
+  idle_timeout: Option<Duration>, // only set when the pool can shrink
+  metrics: Metrics,
+  closed: AtomicBool, // set once the pool stops taking new jobs
+}
+
+impl ThreadPool {
+  /// Create a new ThreadPool.
+  ///
+  /// The size is the number of threads in the pool.
+  ///
+  /// # Panics
+  ///
+  /// The `new` function will panic if the size is zero or the threads can't be started.
+  /// Use `build` to handle those cases yourself.
+  pub fn new(size: usize) -> ThreadPool {
+    assert!(size > 0); // doesn't make sense to have 0 threads
+    ThreadPool::build(size).unwrap()
+  }
+
+  /// Create a new ThreadPool, returning an error instead of panicking if the size is zero or
+  /// the operating system won't give us the threads.
+  pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+    ThreadPool::builder(size).build()
+  }
+
+  /// Starts configuring a pool of `size` threads with more options than `new` offers.
+  pub fn builder(size: usize) -> ThreadPoolBuilder {
+    ThreadPoolBuilder {
+      size,
+      max_workers: None,
+      idle_timeout: DEFAULT_IDLE_TIMEOUT,
+      queue: None,
+      scheduler: Scheduler::default(),
+    }
+  }
+
+  /// Queues `f` to run on one of the pool's threads.
+  ///
+  /// # Errors
+  ///
+  /// Returns `ExecuteError::ShuttingDown` if the pool has been closed and won't run new jobs,
+  /// and `ExecuteError::QueueFull` if the queue is full and the pool was built with
+  /// `OverflowPolicy::Reject`.
+  pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError> // define to take a closure as parameter
+    where
+      F: FnOnce() + Send + 'static, // FnOnce is one of three possible traits to use
+    {
+      if self.is_closed() {
+        return Err(ExecuteError::ShuttingDown);
+      }
+      // count the job before sending it, so a worker can't take it off the queue before it's counted
+      let queued_jobs = &self.shared.metrics.queued_jobs;
+      queued_jobs.fetch_add(1, Ordering::Relaxed);
+      self.grow_if_needed(); // before sending, so a full queue with OverflowPolicy::Block gets help
+      let sent = self.send(Message::NewJob(Box::new(f), Instant::now()));
+      if sent.is_err() {
+        queued_jobs.fetch_sub(1, Ordering::Relaxed);
+      }
+      sent
+    }
+
+  // adds a worker if jobs are piling up faster than the idle workers can take them
+  fn grow_if_needed(&self) {
+    let shared = &self.shared;
+    if shared.max_workers <= shared.min_workers {
+      return; // fixed size pool
+    }
+    let mut workers = lock(&shared.workers);
+    let idle = workers.len().saturating_sub(shared.metrics.active_workers.load(Ordering::Relaxed));
+    if workers.len() >= shared.max_workers || shared.metrics.queued_jobs.load(Ordering::Relaxed) <= idle {
+      return;
+    }
+
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    match Worker::new(id, Arc::clone(shared)) {
+      Ok(worker) => {
+        log_event!(Level::Debug, "Queue is backing up, added worker {}.", id);
+        workers.push(worker);
+      }
+      Err(e) => log_event!(Level::Warn, "Failed to add a worker, carrying on with {}: {}", workers.len(), e),
+    }
+  }
+
+  // puts a message on the queue, dealing with a full queue the way the pool was configured to
+  fn send(&self, mut message: Message) -> Result<(), ExecuteError> {
+      let policy = match &self.sender {
+        JobSender::Unbounded(sender) => {
+          return sender.send(message).map_err(|_| ExecuteError::ShuttingDown);
+        }
+        JobSender::Bounded(_, policy) | JobSender::Stealing(_, policy) => *policy,
+      };
+
+      loop {
+        let sent = match &self.sender {
+          JobSender::Bounded(sender, _) => sender.try_send(message),
+          JobSender::Stealing(deques, _) => deques.try_push(message).map_err(mpsc::TrySendError::Full),
+          JobSender::Unbounded(_) => unreachable!("unbounded queues are never full"),
+        };
+        message = match sent {
+          Ok(()) => return Ok(()),
+          Err(mpsc::TrySendError::Disconnected(_)) => return Err(ExecuteError::ShuttingDown),
+          Err(mpsc::TrySendError::Full(message)) => message,
+        };
+        match policy {
+          OverflowPolicy::Block => return self.send_blocking(message),
+          OverflowPolicy::Reject => return Err(ExecuteError::QueueFull),
+          OverflowPolicy::DropOldest => {
+            // pull the front job off the queue ourselves. a worker waiting in recv() keeps the
+            // receiver lock while it sleeps, and if the queue emptied since our try nothing may
+            // ever come to wake it, so never wait on that lock. a busy lock means a worker is
+            // about to take a job, or the queue has room again, so just try sending again.
+            // only terminate() sends Terminate, and it can't run while we hold &self
+            let oldest = match &self.shared.queue {
+              Queue::Channel(receiver) => match receiver.try_lock() {
+                Ok(receiver) => receiver.try_recv().ok(),
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().try_recv().ok(),
+                Err(TryLockError::WouldBlock) => {
+                  thread::yield_now();
+                  continue;
+                }
+              },
+              Queue::Stealing(deques) => deques.take_oldest(),
+            };
+            if let Some(Message::NewJob(..)) = oldest {
+              self.shared.metrics.queued_jobs.fetch_sub(1, Ordering::Relaxed);
+              self.shared.metrics.dropped_jobs.fetch_add(1, Ordering::Relaxed);
+            }
+            drop(oldest); // outside the lock, dropping a job can mean closing a connection
+          }
+        }
+      }
+  }
+
+  // waits for room on the queue, however long that takes
+  fn send_blocking(&self, message: Message) -> Result<(), ExecuteError> {
+    match &self.sender {
+      JobSender::Unbounded(sender) => sender.send(message).map_err(|_| ExecuteError::ShuttingDown),
+      JobSender::Bounded(sender, _) => sender.send(message).map_err(|_| ExecuteError::ShuttingDown),
+      JobSender::Stealing(deques, _) => {
+        deques.push(message);
+        Ok(())
+      }
+    }
+  }
+
+  /// Queues `f` to run on one of the pool's threads and returns a handle for getting its
+  /// return value back.
+  ///
+  /// # Errors
+  ///
+  /// Fails in the same cases as `execute`.
+  pub fn spawn<F, R>(&self, f: F) -> Result<JobHandle<R>, ExecuteError>
+    where
+      F: FnOnce() -> R + Send + 'static,
+      R: Send + 'static,
+    {
+      let (sender, receiver) = mpsc::channel();
+      self.execute(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let panicked = result.is_err();
+        let _ = sender.send(result); // fails if the handle was dropped, which is fine
+        if panicked {
+          // the payload went to the handle, but the worker still needs to know so it counts
+          // the panic and respawns. resume_unwind skips the panic hook so nothing prints twice
+          panic::resume_unwind(Box::new("spawned job panicked"));
+        }
+      })?;
+      Ok(JobHandle::new(receiver))
+    }
+
+  /// Runs `f` with a Scope for queueing jobs that borrow from the caller, then waits for every one
+  /// of them to finish before returning what `f` returned.
+  ///
+  /// # Panics
+  ///
+  /// If `f` or any of the jobs panic, the first panic carries on from here once all the jobs are
+  /// done.
+  ///
+  /// Waiting ties up the calling thread, so calling this from a job on the same pool can
+  /// deadlock once every worker is waiting on a scope of its own.
+  pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+      F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+      scope::run(self, f)
+    }
+
+  /// Stops the pool from taking new jobs. Jobs that were already queued still run.
+  pub fn close(&self) {
+    self.shared.closed.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.shared.closed.load(Ordering::SeqCst)
+  }
+
+  /// How many jobs have panicked since the pool was created.
+  ///
+  /// A panicking job doesn't take its worker down with it: the panic is caught, counted here,
+  /// and the worker's thread is replaced with a fresh one.
+  pub fn panicked_jobs(&self) -> u64 {
+    self.shared.metrics.panicked_jobs.load(Ordering::Relaxed)
+  }
+
+  /// A snapshot of the pool's counters and job latencies.
+  pub fn stats(&self) -> PoolStats {
+    self.shared.metrics.snapshot()
+  }
+
+  /// A handle for reading `stats` from other threads without holding on to the pool itself.
+  pub fn monitor(&self) -> PoolMonitor {
+    PoolMonitor { shared: Arc::clone(&self.shared) }
+  }
+}
+
+impl Drop for ThreadPool {
+  fn drop(&mut self) {
+    self.terminate(None);
+  }
+}
+
+impl ThreadPool {
+  /// Shuts the pool down, giving queued and running jobs up to `timeout` to finish.
+  ///
+  /// Returns `false` if some workers were still busy when the time ran out. Those threads are
+  /// left running in the background instead of being waited on.
+  pub fn shutdown_timeout(mut self, timeout: Duration) -> bool {
+    self.terminate(Some(Instant::now() + timeout))
+  }
+
+  // sends every worker a terminate message and joins them, waiting no later than `deadline`
+  fn terminate(&mut self, deadline: Option<Instant>) -> bool {
+    if self.terminated {
+      return true; // already shut down by shutdown_timeout, nothing left for drop to do
+    }
+    self.terminated = true;
+    self.close(); // from here on idle workers stay put, so the list we take below is complete
+    let mut workers = mem::take(&mut *lock(&self.shared.workers));
+
+    log_event!(Level::Debug, "Sending terminate message to all workers.");
+
+    let mut unsent = 0;
+    if let JobSender::Stealing(deques, _) = &self.sender {
+      deques.terminate(); // the workers leave once they've emptied the deques
+    } else {
+      for _ in &workers { // send terminate N times, so N threads will receive one each
+        if !self.send_terminate(deadline) {
+          unsent += 1;
+        }
+      }
+    }
+    log_event!(Level::Info, "Shutting down all workers.");
+    if unsent > 0 {
+      log_event!(Level::Warn, "The queue stayed full, {} workers were never told to stop.", unsent);
+    }
+
+    // need two loops because when we send terminate we don't know which thread will get it
+    // it may not be the same thread that we call thread.join() on, and join() is blocking,
+    // so we may be blocking on a thread that didn't receive a terminate yet
+    let mut all_finished = unsent == 0;
+    for worker in &mut workers {
+      log_event!(Level::Debug, "Shutting down worker {}", worker.id);
+      // a worker that respawned stores its replacement in the slot before exiting,
+      // so keep joining until the slot stays empty
+      loop {
+        // take the handle in its own statement so the slot isn't locked while we join,
+        // otherwise a worker trying to store its replacement would deadlock with us
+        let thread = match lock(&worker.thread).take() {
+          Some(thread) => thread,
+          None => break,
+        };
+        if let Some(deadline) = deadline {
+          while !thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+          }
+          if !thread.is_finished() { // dropping the handle detaches the thread
+            log_event!(Level::Warn, "Worker {} is still busy, leaving it behind.", worker.id);
+            all_finished = false;
+            break;
+          }
+        }
+        // we know all threads have received a terminate from previous loop. join() only
+        // errors if the thread panicked, and jobs can't panic the thread anymore
+        let _ = thread.join();
+      }
+    }
+    all_finished
+  }
+
+  // queues a Terminate for one worker. a full queue just means we wait our turn, but with a
+  // deadline only until then, since workers stuck in long jobs may never make room. returns
+  // false if it never went in
+  fn send_terminate(&self, deadline: Option<Instant>) -> bool {
+    let (sender, deadline) = match (&self.sender, deadline) {
+      (JobSender::Bounded(sender, _), Some(deadline)) => (sender, deadline),
+      _ => return self.send_blocking(Message::Terminate).is_ok(),
+    };
+    let mut message = Message::Terminate;
+    loop {
+      message = match sender.try_send(message) {
+        Ok(()) => return true,
+        Err(mpsc::TrySendError::Disconnected(_)) => return false,
+        Err(mpsc::TrySendError::Full(message)) => message,
+      };
+      if Instant::now() >= deadline {
+        return false;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+}
+
+// a poisoned mutex only means another thread panicked while holding it. none of the data we
+// guard can be left half updated by that, so just carry on with it
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+type ThreadSlot = Arc<Mutex<Option<thread::JoinHandle<()>>>>;
+
+// Worker is our light wrapper around a thread
+struct Worker {
+  id: usize,
+  thread: ThreadSlot, // shared with the thread itself so it can swap in its replacement
+}
+
+impl Worker {
+  pub fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+    let thread: ThreadSlot = Arc::new(Mutex::new(None));
+    Worker::spawn(id, shared, Arc::clone(&thread))?;
+    Ok(Worker { id, thread })
+  }
+
+  // starts a thread for worker `id` and stores its handle in `slot`
+  fn spawn(id: usize, shared: Arc<Shared>, slot: ThreadSlot) -> io::Result<()> {
+    // hold the slot while spawning so the new thread can't store a replacement of its own
+    // before we've stored it
+    let mut guard = lock(&slot);
+    let thread_slot = Arc::clone(&slot);
+    let thread = thread::Builder::new()
+      .name(format!("worker-{}", id))
+      .spawn(move || Worker::run(id, shared, thread_slot))?;
+    *guard = Some(thread);
+    Ok(())
+  }
+
+  fn run(id: usize, shared: Arc<Shared>, slot: ThreadSlot) {
+    let metrics = &shared.metrics;
+    metrics.workers.fetch_add(1, Ordering::Relaxed);
+    loop { // loop forever to listen for incomming tasks
+      let received = match (&shared.queue, shared.idle_timeout) { // recv() blocks if no work present
+        (Queue::Channel(receiver), None) => lock(receiver).recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        (Queue::Channel(receiver), Some(timeout)) => lock(receiver).recv_timeout(timeout),
+        (Queue::Stealing(deques), timeout) => deques.pop(id, timeout),
+      };
+      let message = match received {
+        Ok(message) => message,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+          if Worker::retire(&shared, id) {
+            log_event!(Level::Debug, "Worker {} was idle too long; exiting.", id);
+            break;
+          }
+          continue;
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => break, // the pool is gone, so no more messages can ever come
+      };
+
+      // by having recv() block this thread holds its place as next in line.
+      // not problem that channel locked. it's only locked while no tasks are being sent across it.
+      // the same goes for idle timeouts: the other workers queue up on the lock, so they time
+      // out and retire one after another rather than all at once.
+      // with work stealing there's no line, the deques hand out jobs to whoever asks first
+      match message {
+        Message::NewJob(job, queued_at) => {
+          log_event!(Level::Debug, "Worker {} got a job; executing.", id);
+          metrics.queued_jobs.fetch_sub(1, Ordering::Relaxed);
+          metrics.queue_wait.record(queued_at.elapsed());
+          metrics.active_workers.fetch_add(1, Ordering::Relaxed);
+          let started = Instant::now();
+
+          // run the code passed to this thread, catching any panic so it can't kill the worker
+          let outcome = panic::catch_unwind(AssertUnwindSafe(job));
+
+          metrics.run_time.record(started.elapsed());
+          metrics.active_workers.fetch_sub(1, Ordering::Relaxed);
+          if outcome.is_ok() {
+            metrics.completed_jobs.fetch_add(1, Ordering::Relaxed);
+          } else {
+            metrics.panicked_jobs.fetch_add(1, Ordering::Relaxed);
+            log_event!(Level::Warn, "Worker {} panicked while running a job; respawning.", id);
+            // the job may have left thread locals in a bad state, so hand over to a fresh thread.
+            // if we can't get one, carrying on in this thread is better than losing the worker
+            match Worker::spawn(id, Arc::clone(&shared), Arc::clone(&slot)) {
+              Ok(()) => break,
+              Err(e) => log_event!(Level::Error, "Worker {} could not respawn, keeping its old thread: {}", id, e),
+            }
+          }
+        }
+        Message::Terminate => {
+          log_event!(Level::Debug, "Worker {} was told to terminate.", id);
+          break; // break infinite loop
+        }
+      }
+    }
+    metrics.workers.fetch_sub(1, Ordering::Relaxed);
+  }
+
+  // takes worker `id` out of the pool if the pool is above its minimum size. checked and done
+  // under the workers lock, so terminate() never counts a worker that is about to leave
+  fn retire(shared: &Shared, id: usize) -> bool {
+    let mut workers = lock(&shared.workers);
+    if shared.closed.load(Ordering::SeqCst) || workers.len() <= shared.min_workers {
+      return false;
+    }
+    workers.retain(|worker| worker.id != id); // drops our handle, which detaches this thread
+    true
+  }
+}
+
+/// Configures a ThreadPool before starting it. Created with `ThreadPool::builder`.
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+  size: usize,
+  max_workers: Option<usize>,
+  idle_timeout: Duration,
+  queue: Option<(usize, OverflowPolicy)>,
+  scheduler: Scheduler,
+}
+
+/// How long a worker above the minimum pool size may sit idle before it exits.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl ThreadPoolBuilder {
+  /// Lets the pool grow past its starting size, up to `max` workers, while jobs are queueing
+  /// up. Extra workers exit again once they have been idle for the idle timeout, shrinking the
+  /// pool back to the size it was built with.
+  pub fn max_workers(mut self, max: usize) -> ThreadPoolBuilder {
+    self.max_workers = Some(max);
+    self
+  }
+
+  /// How long a worker beyond the minimum waits for a job before exiting.
+  pub fn idle_timeout(mut self, timeout: Duration) -> ThreadPoolBuilder {
+    self.idle_timeout = timeout;
+    self
+  }
+
+  /// Limits the number of jobs waiting for a worker to `capacity`, using `policy` once that
+  /// many are queued. Without this the queue grows without limit.
+  pub fn queue_capacity(mut self, capacity: usize, policy: OverflowPolicy) -> ThreadPoolBuilder {
+    self.queue = Some((capacity, policy));
+    self
+  }
+
+  /// Picks how jobs get from `execute` to the workers. `Scheduler::WorkStealing` is meant for
+  /// many cores and lots of short jobs, where workers would otherwise queue up on the channel's
+  /// lock. `cargo bench --bench pool` compares the two on the machine at hand.
+  pub fn scheduler(mut self, scheduler: Scheduler) -> ThreadPoolBuilder {
+    self.scheduler = scheduler;
+    self
+  }
+
+  pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+    if self.size == 0 {
+      return Err(PoolCreationError::ZeroSize);
+    }
+    let max_workers = self.max_workers.unwrap_or(self.size);
+    if max_workers < self.size {
+      return Err(PoolCreationError::MaxBelowMin);
+    }
+
+    let (sender, queue) = match (self.scheduler, self.queue) {
+      (_, Some((0, _))) => return Err(PoolCreationError::ZeroCapacity),
+      (Scheduler::Channel, None) => {
+        let (sender, receiver) = mpsc::channel();
+        (JobSender::Unbounded(sender), Queue::Channel(Mutex::new(receiver)))
+      }
+      (Scheduler::Channel, Some((capacity, policy))) => {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (JobSender::Bounded(sender, policy), Queue::Channel(Mutex::new(receiver)))
+      }
+      (Scheduler::WorkStealing, queue) => {
+        // a deque for every worker the pool may grow to. workers come and go, but any of them
+        // can empty any deque, so one with no owner right now just gets stolen from
+        let deques = Arc::new(Deques::new(max_workers, queue.map(|(capacity, _)| capacity)));
+        let policy = queue.map_or(OverflowPolicy::Block, |(_, policy)| policy);
+        (JobSender::Stealing(Arc::clone(&deques), policy), Queue::Stealing(deques))
+      }
+    };
+
+    let shared = Arc::new(Shared {
+      queue,
+      workers: Mutex::new(Vec::with_capacity(max_workers)),
+      next_id: AtomicUsize::new(self.size),
+      min_workers: self.size,
+      max_workers,
+      idle_timeout: if max_workers > self.size { Some(self.idle_timeout) } else { None },
+      metrics: Metrics::default(),
+      closed: AtomicBool::new(false),
+    });
+
+    for id in 0..self.size {
+      // if this fails the workers already started see the sender drop and exit on their own.
+      // deques don't have a sender to drop, so they're told outright
+      let worker = Worker::new(id, Arc::clone(&shared)).map_err(|e| {
+        if let JobSender::Stealing(deques, _) = &sender {
+          deques.terminate();
+        }
+        PoolCreationError::Spawn(e)
+      })?;
+      lock(&shared.workers).push(worker);
+    }
+    Ok(ThreadPool{ sender, shared, terminated: false })
+  }
+}
+
+/// The reasons `ThreadPool::build` can fail.
+#[derive(Debug)]
+pub enum PoolCreationError {
+  ZeroSize,
+  ZeroCapacity,
+  MaxBelowMin,
+  Spawn(io::Error), // the operating system refused to start a thread
+}
+
+impl fmt::Display for PoolCreationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PoolCreationError::ZeroSize => write!(f, "a thread pool needs at least one thread"),
+      PoolCreationError::ZeroCapacity => write!(f, "a bounded job queue needs room for at least one job"),
+      PoolCreationError::MaxBelowMin => write!(f, "max_workers can't be less than the pool's starting size"),
+      PoolCreationError::Spawn(e) => write!(f, "failed to start a worker thread: {}", e),
+    }
+  }
+}
+
+impl Error for PoolCreationError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      PoolCreationError::Spawn(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+/// The reasons `ThreadPool::execute` can refuse a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+  ShuttingDown,
+  QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ExecuteError::ShuttingDown => write!(f, "the thread pool is shutting down"),
+      ExecuteError::QueueFull => write!(f, "the thread pool's job queue is full"),
+    }
+  }
+}
+
+impl Error for ExecuteError {}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message { // to indicate if thread should take a new job or exit their infinite loop
+  NewJob(Job, Instant), // the job and when it was queued
+  Terminate,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn survives_panicking_jobs() {
+    let pool = ThreadPool::new(2);
+    for _ in 0..4 {
+      pool.execute(|| panic!("job failed on purpose")).unwrap();
+    }
+
+    // every worker has panicked at least once, yet the pool still runs jobs on all of them
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..4 {
+      let sender = sender.clone();
+      pool.execute(move || sender.send(i).unwrap()).unwrap();
+    }
+    let mut results: Vec<i32> = receiver.iter().take(4).collect();
+    results.sort();
+    assert_eq!(results, vec![0, 1, 2, 3]);
+
+    // the last panic may still be being counted while the other worker finished the rest
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.panicked_jobs() < 4 && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(pool.panicked_jobs(), 4);
+  }
+
+  #[test]
+  fn shutdown_waits_for_jobs() {
+    let pool = ThreadPool::new(1);
+    let (sender, receiver) = mpsc::channel();
+    pool.execute(move || {
+      thread::sleep(Duration::from_millis(50));
+      sender.send(()).unwrap();
+    }).unwrap();
+    assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+    assert!(receiver.try_recv().is_ok());
+  }
+
+  #[test]
+  fn build_errors() {
+    assert!(matches!(ThreadPool::build(0), Err(PoolCreationError::ZeroSize)));
+
+    let pool = ThreadPool::build(1).unwrap();
+    pool.close();
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::ShuttingDown));
+  }
+
+  // occupies the only worker until the returned sender is dropped or sent to
+  fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+    let (release, wait) = mpsc::channel();
+    let (started, has_started) = mpsc::channel();
+    pool.execute(move || {
+      started.send(()).unwrap();
+      let _ = wait.recv();
+    }).unwrap();
+    has_started.recv().unwrap(); // make sure the job left the queue before filling it
+    release
+  }
+
+  #[test]
+  fn bounded_queue_rejects() {
+    let pool = ThreadPool::builder(1).queue_capacity(2, OverflowPolicy::Reject).build().unwrap();
+    let release = block_worker(&pool);
+
+    assert!(pool.execute(|| {}).is_ok());
+    assert!(pool.execute(|| {}).is_ok());
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+    drop(release);
+  }
+
+  #[test]
+  fn bounded_queue_drops_oldest() {
+    let pool = ThreadPool::builder(1).queue_capacity(2, OverflowPolicy::DropOldest).build().unwrap();
+    let release = block_worker(&pool);
+
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..4 {
+      let sender = sender.clone();
+      pool.execute(move || sender.send(i).unwrap()).unwrap();
+    }
+    drop(sender);
+    drop(release);
+    drop(pool); // wait for everything queued to run
+
+    assert_eq!(receiver.iter().collect::<Vec<i32>>(), vec![2, 3]); // 0 and 1 made room
+  }
+
+  #[test]
+  fn drop_oldest_never_waits_on_idle_workers() {
+    // workers keep draining the queue between a full try_send and the drop, which used to leave
+    // execute waiting on the receiver lock held by a worker asleep in recv()
+    let pool = ThreadPool::builder(4).queue_capacity(1, OverflowPolicy::DropOldest).build().unwrap();
+    let (done, finished) = mpsc::channel();
+    let submitter = thread::spawn(move || {
+      for _ in 0..20_000 {
+        pool.execute(|| {}).unwrap();
+      }
+      done.send(()).unwrap();
+    });
+    assert!(finished.recv_timeout(Duration::from_secs(20)).is_ok(), "execute got stuck");
+    submitter.join().unwrap();
+  }
+
+  #[test]
+  fn zero_capacity_queue() {
+    let result = ThreadPool::builder(1).queue_capacity(0, OverflowPolicy::Block).build();
+    assert!(matches!(result, Err(PoolCreationError::ZeroCapacity)));
+  }
+
+  #[test]
+  fn spawn_returns_values() {
+    let pool = ThreadPool::new(2);
+    let handles: Vec<JobHandle<u64>> = (1..=10u64)
+      .map(|n| pool.spawn(move || (1..=n).product()).unwrap())
+      .collect();
+    let results: Vec<u64> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+    assert_eq!(results[4], 120);
+    assert_eq!(results[9], 3_628_800);
+  }
+
+  #[test]
+  fn spawn_reports_panics() {
+    let pool = ThreadPool::new(1);
+    let handle = pool.spawn(|| -> i32 { panic!("bad input") }).unwrap();
+    let error = handle.join().unwrap_err();
+    assert_eq!(error.panic_message(), Some("bad input"));
+
+    // the pool still works afterwards
+    assert_eq!(pool.spawn(|| 7).unwrap().join().unwrap(), 7);
+  }
+
+  #[test]
+  fn try_join_and_timeout() {
+    let pool = ThreadPool::new(1);
+    let release = block_worker(&pool);
+
+    let mut handle = pool.spawn(|| "done").unwrap();
+    assert!(handle.try_join().is_none()); // still queued behind the blocked worker
+    assert!(handle.join_timeout(Duration::from_millis(20)).is_none());
+
+    drop(release);
+    assert_eq!(handle.join_timeout(Duration::from_secs(5)).unwrap().unwrap(), "done");
+  }
+
+  #[test]
+  fn dropped_jobs_are_cancelled() {
+    let pool = ThreadPool::builder(1).queue_capacity(1, OverflowPolicy::DropOldest).build().unwrap();
+    let release = block_worker(&pool);
+
+    let first = pool.spawn(|| 1).unwrap();
+    let second = pool.spawn(|| 2).unwrap(); // pushes the first one out of the queue
+    drop(release);
+    assert!(matches!(first.join(), Err(JoinError::Cancelled)));
+    assert_eq!(second.join().unwrap(), 2);
+  }
+
+  #[test]
+  fn stats_count_jobs() {
+    let pool = ThreadPool::new(2);
+    let release = block_worker(&pool);
+
+    let stats = pool.stats();
+    assert_eq!(stats.workers, 2);
+    assert_eq!(stats.active_workers, 1);
+
+    for _ in 0..3 {
+      pool.spawn(|| ()).unwrap().join().unwrap();
+    }
+    pool.spawn(|| panic!("counted")).unwrap().join().unwrap_err();
+    drop(release);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.stats().active_workers > 0 && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(10));
+    }
+    let stats = pool.stats();
+    assert_eq!(stats.queued_jobs, 0);
+    assert_eq!(stats.completed_jobs, 4); // three spawned jobs plus block_worker's
+    assert_eq!(stats.panicked_jobs, 1);
+    assert_eq!(stats.run_time.count(), 5);
+    assert_eq!(stats.queue_wait.count(), 5);
+  }
+
+  // waits until `check` passes or a few seconds go by
+  fn wait_for(check: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check() && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(10));
+    }
+    check()
+  }
+
+  #[test]
+  fn grows_under_load_and_shrinks_when_idle() {
+    let pool = ThreadPool::builder(1)
+      .max_workers(3)
+      .idle_timeout(Duration::from_millis(50))
+      .build()
+      .unwrap();
+
+    // four jobs that all wait on the same channel, so they pile up
+    let (release, wait) = mpsc::channel::<()>();
+    let wait = Arc::new(Mutex::new(wait));
+    for _ in 0..4 {
+      let wait = Arc::clone(&wait);
+      pool.execute(move || { let _ = wait.lock().unwrap().recv(); }).unwrap();
+    }
+    assert!(wait_for(|| pool.stats().workers == 3)); // grew, but not past the maximum
+
+    drop(release); // let everything finish, then the extra workers go idle and leave
+    assert!(wait_for(|| pool.stats().workers == 1 && pool.stats().queued_jobs == 0));
+    assert_eq!(pool.spawn(|| 5).unwrap().join().unwrap(), 5); // and the one left still works
+  }
+
+  fn stealing_pool(size: usize) -> ThreadPoolBuilder {
+    ThreadPool::builder(size).scheduler(Scheduler::WorkStealing)
+  }
+
+  #[test]
+  fn work_stealing_runs_everything() {
+    let pool = Arc::new(stealing_pool(4).build().unwrap());
+    let handles: Vec<JobHandle<u64>> = (1..=1000u64).map(|n| pool.spawn(move || n * 2).unwrap()).collect();
+    let total: u64 = handles.into_iter().map(|handle| handle.join().unwrap()).sum();
+    assert_eq!(total, 1000 * 1001);
+
+    // jobs queued from inside a job go on the worker's own deque, and still get run
+    let (sender, receiver) = mpsc::channel();
+    let inner = Arc::clone(&pool);
+    pool.execute(move || {
+      for i in 0..10 {
+        let sender = sender.clone();
+        inner.execute(move || sender.send(i).unwrap()).unwrap();
+      }
+    }).unwrap();
+    let mut results: Vec<i32> = receiver.iter().take(10).collect();
+    results.sort();
+    assert_eq!(results, (0..10).collect::<Vec<_>>());
+
+    pool.execute(|| panic!("job failed on purpose")).unwrap();
+    assert_eq!(pool.spawn(|| 7).unwrap().join().unwrap(), 7);
+  }
+
+  #[test]
+  fn work_stealing_bounded_queue() {
+    let pool = stealing_pool(1).queue_capacity(2, OverflowPolicy::Reject).build().unwrap();
+    let release = block_worker(&pool);
+    assert!(pool.execute(|| {}).is_ok());
+    assert!(pool.execute(|| {}).is_ok());
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+    drop(release);
+
+    let pool = stealing_pool(1).queue_capacity(2, OverflowPolicy::DropOldest).build().unwrap();
+    let release = block_worker(&pool);
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..4 {
+      let sender = sender.clone();
+      pool.execute(move || sender.send(i).unwrap()).unwrap();
+    }
+    drop(sender);
+    drop(release);
+    drop(pool);
+    assert_eq!(receiver.iter().collect::<Vec<i32>>(), vec![2, 3]);
+  }
+
+  #[test]
+  fn work_stealing_grows_and_shrinks() {
+    let pool = stealing_pool(1).max_workers(3).idle_timeout(Duration::from_millis(50)).build().unwrap();
+    let (release, wait) = mpsc::channel::<()>();
+    let wait = Arc::new(Mutex::new(wait));
+    for _ in 0..4 {
+      let wait = Arc::clone(&wait);
+      pool.execute(move || { let _ = wait.lock().unwrap().recv(); }).unwrap();
+    }
+    assert!(wait_for(|| pool.stats().workers == 3));
+
+    drop(release);
+    assert!(wait_for(|| pool.stats().workers == 1 && pool.stats().queued_jobs == 0));
+    assert_eq!(pool.spawn(|| 5).unwrap().join().unwrap(), 5);
+    assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+  }
+
+  #[test]
+  fn max_below_min() {
+    let result = ThreadPool::builder(4).max_workers(2).build();
+    assert!(matches!(result, Err(PoolCreationError::MaxBelowMin)));
+  }
+}
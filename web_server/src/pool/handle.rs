@@ -0,0 +1,119 @@
+/*
+handles for getting a value back out of a job run with ThreadPool::spawn
+*/
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// The result of a spawned job, or a way to wait for it. Much like `std::thread::JoinHandle`,
+/// except the job runs on one of the pool's threads instead of a thread of its own.
+///
+/// Dropping the handle doesn't cancel the job, its result is just thrown away.
+pub struct JobHandle<R> {
+  receiver: Option<mpsc::Receiver<thread::Result<R>>>, // None once the result has been taken
+}
+
+impl<R> JobHandle<R> {
+  pub(crate) fn new(receiver: mpsc::Receiver<thread::Result<R>>) -> JobHandle<R> {
+    JobHandle { receiver: Some(receiver) }
+  }
+
+  /// Blocks until the job has finished and returns what it returned.
+  ///
+  /// # Errors
+  ///
+  /// `JoinError::Panicked` if the job panicked, `JoinError::Cancelled` if the pool threw the
+  /// job away without running it.
+  pub fn join(mut self) -> Result<R, JoinError> {
+    let received = self.receiver().recv();
+    self.receiver = None;
+    convert(received.map_err(|_| mpsc::RecvTimeoutError::Disconnected))
+  }
+
+  /// Returns the job's result if it has finished, or `None` if it is still queued or running.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the result was already returned by an earlier call.
+  pub fn try_join(&mut self) -> Option<Result<R, JoinError>> {
+    let received = self.receiver().try_recv().map_err(|e| match e {
+      mpsc::TryRecvError::Empty => mpsc::RecvTimeoutError::Timeout,
+      mpsc::TryRecvError::Disconnected => mpsc::RecvTimeoutError::Disconnected,
+    });
+    self.take(received)
+  }
+
+  /// Waits up to `timeout` for the job to finish. Returns `None` if it still hasn't.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the result was already returned by an earlier call.
+  pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<R, JoinError>> {
+    let received = self.receiver().recv_timeout(timeout);
+    self.take(received)
+  }
+
+  fn receiver(&self) -> &mpsc::Receiver<thread::Result<R>> {
+    self.receiver.as_ref().expect("the job's result was already taken from this JobHandle")
+  }
+
+  // turns a receive attempt into a result, forgetting the receiver once it has given us one
+  fn take(&mut self, received: Result<thread::Result<R>, mpsc::RecvTimeoutError>) -> Option<Result<R, JoinError>> {
+    if let Err(mpsc::RecvTimeoutError::Timeout) = received {
+      return None;
+    }
+    self.receiver = None;
+    Some(convert(received))
+  }
+}
+
+fn convert<R>(received: Result<thread::Result<R>, mpsc::RecvTimeoutError>) -> Result<R, JoinError> {
+  match received {
+    Ok(Ok(value)) => Ok(value),
+    Ok(Err(payload)) => Err(JoinError::Panicked(payload)),
+    // the job was dropped without sending anything, so it never ran
+    Err(_) => Err(JoinError::Cancelled),
+  }
+}
+
+/// Why a spawned job didn't produce a value.
+pub enum JoinError {
+  Panicked(Box<dyn Any + Send + 'static>), // the value the job panicked with
+  Cancelled,                               // the pool dropped the job before running it
+}
+
+impl JoinError {
+  /// The panic message, if the job panicked with a string like `panic!` does.
+  pub fn panic_message(&self) -> Option<&str> {
+    match self {
+      JoinError::Panicked(payload) => payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|message| message.as_str())),
+      JoinError::Cancelled => None,
+    }
+  }
+}
+
+impl fmt::Debug for JoinError { // Box<dyn Any> isn't Debug, so show the message instead
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      JoinError::Panicked(_) => write!(f, "Panicked({:?})", self.panic_message().unwrap_or("..")),
+      JoinError::Cancelled => write!(f, "Cancelled"),
+    }
+  }
+}
+
+impl fmt::Display for JoinError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (self, self.panic_message()) {
+      (JoinError::Panicked(_), Some(message)) => write!(f, "job panicked: {}", message),
+      (JoinError::Panicked(_), None) => write!(f, "job panicked"),
+      (JoinError::Cancelled, _) => write!(f, "job was cancelled before it ran"),
+    }
+  }
+}
+
+impl Error for JoinError {}
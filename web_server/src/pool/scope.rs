@@ -0,0 +1,191 @@
+/*
+scoped jobs: ThreadPool::scope lets jobs borrow from the caller's stack, the way std::thread::scope
+does for threads, because it doesn't return until every job queued on the Scope is done with.
+
+`execute` on its own needs 'static jobs, since nothing stops the caller from returning (and freeing
+what the job borrowed) while the job is still queued. here the scope counts its jobs in and out,
+and the count only goes down once a job can no longer touch what it borrowed: after it has run, or
+after it's been dropped without running, say by OverflowPolicy::DropOldest
+*/
+
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use super::{lock, ExecuteError, ThreadPool};
+
+/// Queues jobs that may borrow anything that outlives the `ThreadPool::scope` call. Handed to
+/// the closure passed to `scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+  pool: &'scope ThreadPool,
+  state: Arc<State>,
+  // invariant over both lifetimes, like std::thread::Scope, so neither can be shortened to let a
+  // job borrow something that doesn't live long enough
+  scope: PhantomData<&'scope mut &'scope ()>,
+  env: PhantomData<&'env mut &'env ()>,
+}
+
+// shared with the jobs. in an Arc rather than on the scope's stack because the last job is still
+// letting go of the lock after the scope has been woken up and returned
+#[derive(Default)]
+struct State {
+  running: Mutex<usize>, // jobs queued and not yet finished or dropped
+  finished: Condvar,
+  panic: Mutex<Option<Box<dyn Any + Send>>>, // the first job to panic's payload
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+  /// Queues `f` to run on one of the pool's threads. Unlike `ThreadPool::execute` it can borrow
+  /// from outside the scope.
+  ///
+  /// # Errors
+  ///
+  /// Fails in the same cases as `ThreadPool::execute`, in which case `f` is dropped unrun.
+  pub fn execute<F>(&'scope self, f: F) -> Result<(), ExecuteError>
+    where
+      F: FnOnce() + Send + 'scope,
+    {
+      *lock(&self.state.running) += 1;
+      let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(f);
+      // SAFETY: the job only ever lives as long as the pool says it can because ScopedJob tells
+      // the scope when it's gone, and `ThreadPool::scope` doesn't return until it has been told
+      // about every job. until then everything 'scope borrows is still there
+      let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+      let mut scoped = ScopedJob { job: Some(job), state: Arc::clone(&self.state) };
+      self.pool.execute(move || scoped.run()) // a refused job is dropped, and counted out, here
+    }
+}
+
+// a job plus the scope it has to report back to, however it ends
+struct ScopedJob {
+  job: Option<Box<dyn FnOnce() + Send>>,
+  state: Arc<State>,
+}
+
+impl ScopedJob {
+  fn run(&mut self) {
+    let job = self.job.take().unwrap();
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+      lock(&self.state.panic).get_or_insert(payload);
+      // the payload goes back to whoever called scope, but the worker still needs to know so it
+      // counts the panic and respawns, same as a spawned job
+      panic::resume_unwind(Box::new("scoped job panicked"));
+    }
+  }
+}
+
+impl Drop for ScopedJob {
+  fn drop(&mut self) {
+    drop(self.job.take()); // an unrun job holds borrows too, so let go of it before counting out
+    let mut running = lock(&self.state.running);
+    *running -= 1;
+    if *running == 0 {
+      self.state.finished.notify_all();
+    }
+  }
+}
+
+// the body of ThreadPool::scope
+pub(super) fn run<'env, F, T>(pool: &ThreadPool, f: F) -> T
+  where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+  {
+    let scope = Scope { pool, state: Arc::default(), scope: PhantomData, env: PhantomData };
+    // even if f panics the jobs it queued are still borrowing, so wait for them before unwinding
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+    let mut running = lock(&scope.state.running);
+    while *running > 0 {
+      running = scope.state.finished.wait(running).unwrap_or_else(PoisonError::into_inner);
+    }
+    drop(running);
+
+    let job_panic = lock(&scope.state.panic).take();
+    match (result, job_panic) {
+      (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+      (Ok(value), None) => value,
+    }
+  }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pool::{OverflowPolicy, Scheduler};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn borrows_from_the_stack() {
+    let pool = ThreadPool::new(3);
+    let numbers: Vec<u64> = (1..=1000).collect();
+    let mut sums = [0u64; 4];
+
+    pool.scope(|s| {
+      for (chunk, sum) in numbers.chunks(250).zip(sums.iter_mut()) {
+        s.execute(move || *sum = chunk.iter().sum()).unwrap();
+      }
+    });
+    assert_eq!(sums, [31375, 93875, 156375, 218875]);
+    assert_eq!(sums.iter().sum::<u64>(), 500_500);
+  }
+
+  #[test]
+  fn waits_for_every_job() {
+    let pool = ThreadPool::builder(2).scheduler(Scheduler::WorkStealing).build().unwrap();
+    let finished = AtomicUsize::new(0);
+    let returned = pool.scope(|s| {
+      for _ in 0..8 {
+        s.execute(|| {
+          thread::sleep(Duration::from_millis(10));
+          finished.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+      }
+      "queued"
+    });
+    assert_eq!(returned, "queued");
+    assert_eq!(finished.load(Ordering::SeqCst), 8);
+  }
+
+  #[test]
+  fn passes_on_panics() {
+    let pool = ThreadPool::new(2);
+    let finished = AtomicUsize::new(0);
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+      pool.scope(|s| {
+        s.execute(|| panic!("bad chunk")).unwrap();
+        s.execute(|| {
+          thread::sleep(Duration::from_millis(20));
+          finished.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+      })
+    }));
+    assert_eq!(outcome.unwrap_err().downcast_ref::<&str>(), Some(&"bad chunk"));
+    assert_eq!(finished.load(Ordering::SeqCst), 1); // the other job still ran first
+    assert_eq!(pool.spawn(|| 7).unwrap().join().unwrap(), 7);
+  }
+
+  #[test]
+  fn dropped_jobs_count_as_done() {
+    let pool = ThreadPool::builder(1).queue_capacity(1, OverflowPolicy::DropOldest).build().unwrap();
+    let (release, wait) = mpsc::channel::<()>();
+    let (started, has_started) = mpsc::channel();
+    let ran = AtomicUsize::new(0);
+    pool.scope(|s| {
+      s.execute(move || { // holds the only worker
+        started.send(()).unwrap();
+        let _ = wait.recv();
+      }).unwrap();
+      has_started.recv().unwrap(); // make sure it left the queue before filling it
+      for _ in 0..3 {
+        s.execute(|| { ran.fetch_add(1, Ordering::SeqCst); }).unwrap(); // each pushes the last out
+      }
+      drop(release);
+    });
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+  }
+}
@@ -0,0 +1,114 @@
+/*
+counters the workers update as they go, and snapshots of them for anyone who wants to look
+*/
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Shared;
+
+/// Upper bounds, in seconds, of the latency histogram buckets. Anything slower lands in an
+/// implicit last bucket with no upper bound.
+pub const LATENCY_BUCKETS: [f64; 11] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+// everything is a relaxed atomic. a snapshot may be a job or two out of step between counters,
+// which is fine for monitoring and much cheaper than a lock on every job
+#[derive(Default)]
+pub(crate) struct Metrics {
+  pub workers: AtomicUsize,
+  pub active_workers: AtomicUsize,
+  pub queued_jobs: AtomicUsize,
+  pub completed_jobs: AtomicU64,
+  pub panicked_jobs: AtomicU64,
+  pub dropped_jobs: AtomicU64,
+  pub queue_wait: AtomicHistogram,
+  pub run_time: AtomicHistogram,
+}
+
+impl Metrics {
+  pub fn snapshot(&self) -> PoolStats {
+    PoolStats {
+      workers: self.workers.load(Ordering::Relaxed),
+      active_workers: self.active_workers.load(Ordering::Relaxed),
+      queued_jobs: self.queued_jobs.load(Ordering::Relaxed),
+      completed_jobs: self.completed_jobs.load(Ordering::Relaxed),
+      panicked_jobs: self.panicked_jobs.load(Ordering::Relaxed),
+      dropped_jobs: self.dropped_jobs.load(Ordering::Relaxed),
+      queue_wait: self.queue_wait.snapshot(),
+      run_time: self.run_time.snapshot(),
+    }
+  }
+}
+
+#[derive(Default)]
+pub(crate) struct AtomicHistogram {
+  buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1], // the extra one is the +Inf bucket
+  sum_nanos: AtomicU64,
+}
+
+impl AtomicHistogram {
+  pub fn record(&self, duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let index = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(LATENCY_BUCKETS.len());
+    self.buckets[index].fetch_add(1, Ordering::Relaxed);
+    self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  fn snapshot(&self) -> Histogram {
+    Histogram {
+      counts: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+      sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+    }
+  }
+}
+
+/// A point in time view of what a ThreadPool is doing. Get one from `ThreadPool::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+  pub workers: usize,        // threads currently in the pool
+  pub active_workers: usize, // of those, how many are running a job right now
+  pub queued_jobs: usize,    // jobs waiting for a free worker
+  pub completed_jobs: u64,   // jobs that ran to the end
+  pub panicked_jobs: u64,    // jobs that panicked
+  pub dropped_jobs: u64,     // jobs thrown away by OverflowPolicy::DropOldest
+  pub queue_wait: Histogram, // how long jobs sat in the queue
+  pub run_time: Histogram,   // how long jobs took to run
+}
+
+/// Job latencies sorted into the buckets in `LATENCY_BUCKETS`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+  pub counts: Vec<u64>, // counts[i] is the jobs that took at most LATENCY_BUCKETS[i], the last is the rest
+  pub sum: Duration,
+}
+
+impl Histogram {
+  /// Number of jobs recorded.
+  pub fn count(&self) -> u64 {
+    self.counts.iter().sum()
+  }
+
+  /// The running totals Prometheus wants: jobs that took at most each bound, ending with all of them.
+  pub fn cumulative(&self) -> Vec<u64> {
+    self.counts.iter()
+      .scan(0, |total, count| {
+        *total += count;
+        Some(*total)
+      })
+      .collect()
+  }
+}
+
+/// A cloneable handle for reading a pool's stats from other threads, e.g. a request handler.
+/// It keeps working after the pool is gone, reporting the final numbers.
+#[derive(Clone)]
+pub struct PoolMonitor {
+  pub(super) shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+  pub fn stats(&self) -> PoolStats {
+    self.shared.metrics.snapshot()
+  }
+}
@@ -0,0 +1,282 @@
+/*
+the queue behind Scheduler::WorkStealing.
+
+with the channel scheduler every worker waits its turn on the one receiver lock, so at high job
+rates the workers spend more time queueing for that lock than running jobs. here each worker has
+a deque of its own instead. jobs from outside the pool are dealt out to the deques in turn, jobs
+queued by a job already running on a worker go on that worker's deque, and a worker whose deque
+runs dry steals from the others before it goes to sleep. a lock is still taken per job, but it's
+one of many, so workers rarely have to wait for one.
+
+deques are taken from the front by their owner and by thieves alike, so jobs still run in roughly
+the order they were queued
+*/
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{lock, Message};
+
+thread_local! {
+  // which Deques this thread is a worker of (by address) and the index of its own deque
+  static HOME: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+pub(super) struct Deques {
+  deques: Vec<Mutex<VecDeque<Message>>>,
+  queued: AtomicUsize, // across all deques. counted before a job goes in, so it can run ahead
+  capacity: Option<usize>,
+  next: AtomicUsize, // the deque the next job from outside the pool goes on
+  terminating: AtomicBool,
+  // sleeping workers and blocked senders wait on these. the counts let the other side skip the
+  // lock when nobody is waiting
+  sleep: Mutex<()>,
+  wake: Condvar,
+  sleepers: AtomicUsize,
+  room: Condvar,
+  blocked_senders: AtomicUsize,
+}
+
+impl Deques {
+  /// `count` deques holding at most `capacity` jobs between them, or any number with `None`.
+  pub fn new(count: usize, capacity: Option<usize>) -> Deques {
+    Deques {
+      deques: (0..count).map(|_| Mutex::new(VecDeque::new())).collect(),
+      queued: AtomicUsize::new(0),
+      capacity,
+      next: AtomicUsize::new(0),
+      terminating: AtomicBool::new(false),
+      sleep: Mutex::new(()),
+      wake: Condvar::new(),
+      sleepers: AtomicUsize::new(0),
+      room: Condvar::new(),
+      blocked_senders: AtomicUsize::new(0),
+    }
+  }
+
+  /// Queues `message`, or hands it back if the deques are already holding `capacity` jobs.
+  pub fn try_push(&self, message: Message) -> Result<(), Message> {
+    let reserved = match self.capacity {
+      Some(capacity) => self.queued
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| if queued < capacity { Some(queued + 1) } else { None })
+        .is_ok(),
+      None => {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        true
+      }
+    };
+    if !reserved {
+      return Err(message);
+    }
+
+    let index = match HOME.with(Cell::get) {
+      Some((deques, home)) if deques == self.address() => home,
+      _ => self.next.fetch_add(1, Ordering::Relaxed) % self.deques.len(),
+    };
+    lock(&self.deques[index]).push_back(message);
+    if self.sleepers.load(Ordering::SeqCst) > 0 {
+      let _sleep = lock(&self.sleep);
+      self.wake.notify_one();
+    }
+    Ok(())
+  }
+
+  /// Queues `message`, waiting for a worker to make room first if the deques are full.
+  pub fn push(&self, mut message: Message) {
+    loop {
+      message = match self.try_push(message) {
+        Ok(()) => return,
+        Err(message) => message,
+      };
+      let sleep = lock(&self.sleep);
+      self.blocked_senders.fetch_add(1, Ordering::SeqCst);
+      if !self.is_full() { // a worker took a job between our try and taking the lock
+        self.blocked_senders.fetch_sub(1, Ordering::SeqCst);
+        continue;
+      }
+      let _sleep = wait(&self.room, sleep);
+      self.blocked_senders.fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+
+  /// Takes the next job for the worker whose deque is `home`: from its own deque if it has one,
+  /// otherwise stolen from another worker's. Waits for one if there are none anywhere.
+  ///
+  /// Fails with `Timeout` once `idle_timeout` goes by without a job, and with `Disconnected`
+  /// once the pool is terminating and every job queued before that has been taken.
+  pub fn pop(&self, home: usize, idle_timeout: Option<Duration>) -> Result<Message, RecvTimeoutError> {
+    let home = home % self.deques.len();
+    HOME.with(|cell| cell.set(Some((self.address(), home))));
+    let deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+
+    loop {
+      let count = self.deques.len();
+      let found = (0..count).find_map(|offset| lock(&self.deques[(home + offset) % count]).pop_front());
+      if let Some(message) = found {
+        self.taken();
+        return Ok(message);
+      }
+
+      // count ourselves as a sleeper before the last look at `queued`, so a job pushed after
+      // that look is sure to see us and send a wake up
+      let sleep = lock(&self.sleep);
+      self.sleepers.fetch_add(1, Ordering::SeqCst);
+      let outcome = if self.queued.load(Ordering::SeqCst) > 0 {
+        None // a job is on its way into a deque, go and get it
+      } else if self.terminating.load(Ordering::SeqCst) {
+        Some(RecvTimeoutError::Disconnected)
+      } else {
+        match deadline {
+          None => {
+            let _sleep = wait(&self.wake, sleep);
+            None
+          }
+          Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+              Some(RecvTimeoutError::Timeout)
+            } else {
+              let _sleep = self.wake.wait_timeout(sleep, remaining).unwrap_or_else(PoisonError::into_inner);
+              None
+            }
+          }
+        }
+      };
+      self.sleepers.fetch_sub(1, Ordering::SeqCst);
+      match outcome {
+        Some(error) => return Err(error),
+        None => thread::yield_now(),
+      }
+    }
+  }
+
+  /// Takes the job that has been queued the longest off of whichever deque it's on.
+  pub fn take_oldest(&self) -> Option<Message> {
+    let (_, oldest) = self.deques.iter().enumerate()
+      .filter_map(|(index, deque)| match lock(deque).front() {
+        Some(Message::NewJob(_, queued_at)) => Some((*queued_at, index)),
+        _ => None,
+      })
+      .min()?;
+    let message = lock(&self.deques[oldest]).pop_front()?; // someone may have beaten us to it
+    self.taken();
+    Some(message)
+  }
+
+  /// Wakes every worker so they can finish off the remaining jobs and exit.
+  pub fn terminate(&self) {
+    self.terminating.store(true, Ordering::SeqCst);
+    let _sleep = lock(&self.sleep);
+    self.wake.notify_all();
+  }
+
+  // a job has left the deques, which makes room for a blocked sender
+  fn taken(&self) {
+    self.queued.fetch_sub(1, Ordering::SeqCst);
+    if self.blocked_senders.load(Ordering::SeqCst) > 0 {
+      let _sleep = lock(&self.sleep);
+      self.room.notify_one();
+    }
+  }
+
+  fn is_full(&self) -> bool {
+    matches!(self.capacity, Some(capacity) if self.queued.load(Ordering::SeqCst) >= capacity)
+  }
+
+  fn address(&self) -> usize {
+    self as *const Deques as usize
+  }
+}
+
+fn wait<'a>(condvar: &Condvar, guard: MutexGuard<'a, ()>) -> MutexGuard<'a, ()> {
+  condvar.wait(guard).unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+
+  fn job(value: usize, sender: &std::sync::mpsc::Sender<usize>) -> Message {
+    let sender = sender.clone();
+    Message::NewJob(Box::new(move || sender.send(value).unwrap()), Instant::now())
+  }
+
+  fn run(message: Message) {
+    match message {
+      Message::NewJob(job, _) => job(),
+      Message::Terminate => panic!("deques never hold a Terminate"),
+    }
+  }
+
+  #[test]
+  fn steals_from_other_deques() {
+    let deques = Deques::new(3, None);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for value in 0..6 {
+      deques.try_push(job(value, &sender)).ok().unwrap(); // dealt out over all three
+    }
+    // one worker can empty every deque, taking its own jobs first
+    for _ in 0..6 {
+      run(deques.pop(1, Some(Duration::from_millis(10))).ok().unwrap());
+    }
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![1, 4, 2, 5, 0, 3]);
+    assert!(matches!(deques.pop(1, Some(Duration::from_millis(10))), Err(RecvTimeoutError::Timeout)));
+  }
+
+  #[test]
+  fn bounded() {
+    let deques = Arc::new(Deques::new(2, Some(2)));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    deques.try_push(job(0, &sender)).ok().unwrap();
+    deques.try_push(job(1, &sender)).ok().unwrap();
+    assert!(deques.try_push(job(2, &sender)).is_err());
+
+    run(deques.take_oldest().unwrap());
+    assert_eq!(receiver.try_recv(), Ok(0));
+
+    // a blocked push goes through once a worker takes a job
+    deques.try_push(job(2, &sender)).ok().unwrap();
+    let pushing = {
+      let (deques, sender) = (Arc::clone(&deques), sender.clone());
+      thread::spawn(move || deques.push(job(3, &sender)))
+    };
+    thread::sleep(Duration::from_millis(20));
+    run(deques.pop(0, None).ok().unwrap());
+    pushing.join().unwrap();
+    run(deques.pop(0, None).ok().unwrap());
+    run(deques.pop(0, None).ok().unwrap());
+    let mut rest: Vec<usize> = receiver.try_iter().collect();
+    rest.sort();
+    assert_eq!(rest, vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn terminate_drains_first() {
+    let deques = Arc::new(Deques::new(2, None));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let sleeper = {
+      let deques = Arc::clone(&deques);
+      thread::spawn(move || {
+        let mut ran = 0;
+        while let Ok(message) = deques.pop(0, None) {
+          run(message);
+          ran += 1;
+        }
+        ran
+      })
+    };
+    thread::sleep(Duration::from_millis(20)); // let it go to sleep on the empty deques
+    for value in 0..5 {
+      deques.try_push(job(value, &sender)).ok().unwrap();
+    }
+    deques.terminate();
+    assert_eq!(sleeper.join().unwrap(), 5);
+    assert_eq!(receiver.try_iter().count(), 5);
+  }
+}
@@ -0,0 +1,489 @@
+/*
+a reverse proxy: requests whose path starts with a prefix are passed on to one of a set of upstream
+servers, and the upstream's answer is streamed back to the client as it arrives.
+
+upstreams take turns (round robin). with health checks on, each one is asked for a path every so
+often in the background, and any that fail, or refuse a connection in between, are skipped until
+they answer again. upstream sees Host set to its own address, the client's address added to
+X-Forwarded-For and the host the client asked for in X-Forwarded-Host.
+
+a Proxy is middleware, so it answers for its prefix ahead of the router:
+
+  server.middleware(Proxy::new("/api", &["127.0.0.1:9001", "127.0.0.1:9002"]).strip_prefix(true))
+*/
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::headers::Headers;
+use crate::log::Level;
+use crate::middleware::Middleware;
+use crate::request::Request;
+use crate::response::Response;
+
+/// How long connecting to an upstream, or any one read or write on the connection, may take.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often upstreams are checked, once health checks are turned on.
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+// most we'll read of an upstream's status line and headers
+const MAX_RESPONSE_HEAD_BYTES: u64 = 64 * 1024;
+
+// headers about one hop's connection rather than the message, which a proxy mustn't pass along
+const HOP_BY_HOP: [&str; 8] = [
+  "Connection", "Keep-Alive", "Proxy-Authenticate", "Proxy-Authorization", "TE", "Trailer",
+  "Transfer-Encoding", "Upgrade",
+];
+
+struct Upstream {
+  addr: String, // host:port
+  healthy: AtomicBool,
+}
+
+impl Upstream {
+  fn set_healthy(&self, healthy: bool) {
+    if self.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+      if healthy {
+        log_event!(Level::Info, "Upstream {} is back up", self.addr);
+      } else {
+        log_event!(Level::Warn, "Upstream {} is down", self.addr);
+      }
+    }
+  }
+}
+
+// the upstreams and whose turn it is, shared with the health check thread
+struct Upstreams {
+  list: Vec<Upstream>,
+  next: AtomicUsize,
+  checked: AtomicBool, // whether something will notice when a down upstream comes back
+}
+
+impl Upstreams {
+  // every upstream in the order to try them for one request: starting from whoever's turn it
+  // is, healthy ones first. the rest are a last resort, in case they've recovered unnoticed
+  fn in_turn(&self) -> Vec<&Upstream> {
+    let start = self.next.fetch_add(1, Ordering::Relaxed);
+    let mut order: Vec<&Upstream> = (0..self.list.len())
+      .map(|i| &self.list[(start + i) % self.list.len()])
+      .collect();
+    order.sort_by_key(|upstream| !upstream.healthy.load(Ordering::SeqCst)); // stable, so turns are kept
+    order
+  }
+}
+
+/// Forwards requests under a path prefix to upstream servers.
+pub struct Proxy {
+  prefix: String, // without a trailing slash, so "" for everything
+  strip_prefix: bool,
+  timeout: Duration,
+  upstreams: Arc<Upstreams>,
+}
+
+impl Proxy {
+  /// Forwards requests for `prefix` and everything under it to `upstreams`, given as host:port.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `upstreams` is empty.
+  pub fn new<S: AsRef<str>>(prefix: &str, upstreams: &[S]) -> Proxy {
+    assert!(!upstreams.is_empty(), "a proxy needs at least one upstream");
+    let list = upstreams.iter()
+      .map(|addr| Upstream { addr: addr.as_ref().to_string(), healthy: AtomicBool::new(true) })
+      .collect();
+    Proxy {
+      prefix: prefix.trim_end_matches('/').to_string(),
+      strip_prefix: false,
+      timeout: DEFAULT_TIMEOUT,
+      upstreams: Arc::new(Upstreams { list, next: AtomicUsize::new(0), checked: AtomicBool::new(false) }),
+    }
+  }
+
+  /// Removes the prefix from the path before passing the request on, so `/api/users` under
+  /// `/api` reaches upstream as `/users`.
+  pub fn strip_prefix(mut self, strip: bool) -> Proxy {
+    self.strip_prefix = strip;
+    self
+  }
+
+  pub fn timeout(mut self, timeout: Duration) -> Proxy {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Checks each upstream every `interval` from a background thread, with a GET for `path`.
+  /// Upstreams that don't answer, or answer with a 5xx, get no requests until they're healthy
+  /// again. The thread stops once the Proxy has been dropped.
+  pub fn health_check(self, path: &str, interval: Duration) -> Proxy {
+    self.upstreams.checked.store(true, Ordering::SeqCst);
+    let upstreams = Arc::downgrade(&self.upstreams);
+    let (path, timeout) = (path.to_string(), self.timeout.min(interval));
+    let spawned = thread::Builder::new().name(String::from("proxy health checks")).spawn(move || loop {
+      match upstreams.upgrade() {
+        Some(upstreams) => {
+          for upstream in &upstreams.list {
+            upstream.set_healthy(check(&upstream.addr, &path, timeout));
+          }
+        }
+        None => return, // the proxy is gone
+      }
+      thread::sleep(interval);
+    });
+    if let Err(e) = spawned {
+      log_event!(Level::Error, "Failed to start proxy health checks: {}", e);
+    }
+    self
+  }
+
+  /// Whether requests for `path` are forwarded.
+  pub fn matches(&self, path: &str) -> bool {
+    match path.strip_prefix(self.prefix.as_str()) {
+      Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.is_empty(),
+      None => false,
+    }
+  }
+
+  /// Sends `request` upstream and returns the answer, with its body still to be read from the
+  /// upstream as it's sent on. Answers 502 Bad Gateway if no upstream can be reached, and 504
+  /// Gateway Timeout if one stops answering.
+  pub fn forward(&self, request: &Request) -> Response {
+    let target = self.upstream_target(&request.target);
+    for upstream in self.upstreams.in_turn() {
+      let stream = match connect(&upstream.addr, self.timeout) {
+        Ok(stream) => stream,
+        Err(e) => {
+          log_event!(Level::Warn, "Couldn't connect to upstream {}: {}", upstream.addr, e);
+          if self.upstreams.checked.load(Ordering::SeqCst) {
+            upstream.set_healthy(false); // the health checks will bring it back
+          }
+          continue; // nothing has been sent, so another upstream can have it
+        }
+      };
+      return match exchange(stream, request, &target, &upstream.addr) {
+        Ok(response) => response,
+        Err(e) => {
+          log_event!(Level::Warn, "Upstream {} failed on {} {}: {}", upstream.addr, request.method, target, e);
+          gateway_error(&e)
+        }
+      };
+    }
+    gateway_error(&io::Error::new(io::ErrorKind::ConnectionRefused, "no upstream could be reached"))
+  }
+
+  // the request target as upstream should see it
+  fn upstream_target(&self, target: &str) -> String {
+    if !self.strip_prefix {
+      return target.to_string();
+    }
+    match target.strip_prefix(self.prefix.as_str()) {
+      Some(rest) if rest.starts_with('/') => rest.to_string(),
+      Some(rest) => format!("/{}", rest), // "/api" or "/api?x" asked for the root
+      None => target.to_string(),
+    }
+  }
+}
+
+impl Middleware for Proxy {
+  fn before(&self, request: &mut Request) -> Option<Response> {
+    if self.matches(request.path()) {
+      Some(self.forward(request))
+    } else {
+      None
+    }
+  }
+}
+
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+  let socket_addr = addr.to_socket_addrs()?.next()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "upstream address didn't resolve"))?;
+  let stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+  Ok(stream)
+}
+
+// sends the request and reads the head of the answer, leaving the body on the connection for
+// the response to stream from
+fn exchange(mut stream: TcpStream, request: &Request, target: &str, upstream: &str) -> io::Result<Response> {
+  let head = format!("{} {} HTTP/1.1\r\n{}\r\n", request.method, target, forwarded_headers(request, upstream));
+  stream.write_all(head.as_bytes())?;
+  stream.write_all(&request.body)?;
+  stream.flush()?;
+
+  let mut reader = BufReader::new(stream);
+  let (status, mut headers) = read_response_head(&mut reader)?;
+
+  let no_body = request.method == "HEAD" || status < 200 || status == 204 || status == 304;
+  let chunked = headers.has_token("Transfer-Encoding", "chunked");
+  let length = headers.get("Content-Length").and_then(|length| length.trim().parse::<u64>().ok());
+  remove_hop_by_hop(&mut headers);
+
+  let mut response = Response::new(status);
+  response.headers = headers;
+  Ok(match (no_body, chunked, length) {
+    (true, _, _) => response,
+    (false, true, _) => {
+      response.headers.remove("Content-Length"); // chunked wins if upstream sent both
+      response.with_reader(Chunked::new(reader), None)
+    }
+    (false, false, Some(length)) => response.with_reader(reader.take(length), Some(length)),
+    (false, false, None) => response.with_reader(reader, None), // the body runs until upstream closes
+  })
+}
+
+fn forwarded_headers(request: &Request, upstream: &str) -> Headers {
+  let mut headers = request.headers.clone();
+  remove_hop_by_hop(&mut headers);
+  if let Some(host) = request.header("Host") {
+    if !headers.contains("X-Forwarded-Host") { // an earlier proxy's is closer to what the client asked for
+      headers.set("X-Forwarded-Host", host);
+    }
+  }
+  if let Some(peer) = request.peer {
+    let forwarded_for = match request.header("X-Forwarded-For") {
+      Some(earlier) => format!("{}, {}", earlier, peer.ip()),
+      None => peer.ip().to_string(),
+    };
+    headers.set("X-Forwarded-For", &forwarded_for);
+  }
+  headers.set("Host", upstream);
+  if !request.body.is_empty() || request.headers.contains("Content-Length") {
+    headers.set("Content-Length", &request.body.len().to_string());
+  }
+  headers.set("Connection", "close"); // one request per connection keeps things simple
+  headers
+}
+
+// drops the hop-by-hop headers, and any others the Connection header names as such
+fn remove_hop_by_hop(headers: &mut Headers) {
+  let named: Vec<String> = headers.get_all("Connection")
+    .flat_map(|value| value.split(','))
+    .map(|name| name.trim().to_string())
+    .filter(|name| !name.is_empty())
+    .collect();
+  for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+    headers.remove(name);
+  }
+}
+
+// the status and headers of upstream's response, skipping any 100 Continue before it
+fn read_response_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Headers)> {
+  let mut limited = reader.take(MAX_RESPONSE_HEAD_BYTES);
+  loop {
+    let status_line = read_line(&mut limited)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next().map(str::parse::<u16>)) {
+      (Some(version), Some(Ok(status))) if version.starts_with("HTTP/1.") && (100..600).contains(&status) => status,
+      _ => return Err(bad_response("malformed status line")),
+    };
+
+    let mut headers = Headers::new();
+    loop {
+      let line = read_line(&mut limited)?;
+      if line.is_empty() {
+        break;
+      }
+      let (name, value) = line.split_once(':').ok_or_else(|| bad_response("malformed header"))?;
+      headers.append(name.trim(), value.trim());
+    }
+    if status >= 200 || status == 101 {
+      return Ok((status, headers));
+    }
+  }
+}
+
+// one line without its line ending, failing if the connection ends first
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+  let mut line = String::new();
+  if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the connection early"));
+  }
+  Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn bad_response(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("bad response from upstream: {}", message))
+}
+
+// what the client gets when upstream lets us down
+fn gateway_error(error: &io::Error) -> Response {
+  let status = match error.kind() {
+    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => 504,
+    _ => 502,
+  };
+  Response::new(status)
+    .with_header("Content-Type", "text/plain; charset=utf-8")
+    .with_body(format!("{} {}\n", status, crate::status::reason_phrase(status)))
+}
+
+// asks upstream for `path` and says whether it answered without a server error
+fn check(addr: &str, path: &str, timeout: Duration) -> bool {
+  let answer = connect(addr, timeout).and_then(|mut stream| {
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr)?;
+    read_response_head(&mut BufReader::new(stream))
+  });
+  matches!(answer, Ok((status, _)) if status < 500)
+}
+
+// decodes a chunked body as it's read, leaving the server to re-chunk it for the client
+struct Chunked<R> {
+  reader: R,
+  remaining: u64, // of the current chunk
+  done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+  fn new(reader: R) -> Chunked<R> {
+    Chunked { reader, remaining: 0, done: false }
+  }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.done || buf.is_empty() {
+      return Ok(0);
+    }
+    if self.remaining == 0 {
+      let line = read_line(&mut (&mut self.reader).take(1024))?;
+      let size = line.split(';').next().unwrap_or("").trim(); // chunk extensions are ignored
+      self.remaining = u64::from_str_radix(size, 16).map_err(|_| bad_response("malformed chunk size"))?;
+      if self.remaining == 0 {
+        // the last chunk, then trailers up to a blank line. we don't pass trailers on
+        while !read_line(&mut (&mut self.reader).take(8 * 1024))?.is_empty() {}
+        self.done = true;
+        return Ok(0);
+      }
+    }
+
+    let wanted = buf.len().min(self.remaining as usize);
+    let read = self.reader.read(&mut buf[..wanted])?;
+    if read == 0 {
+      return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the connection mid-chunk"));
+    }
+    self.remaining -= read as u64;
+    if self.remaining == 0 && !read_line(&mut (&mut self.reader).take(2))?.is_empty() {
+      return Err(bad_response("chunk longer than its size"));
+    }
+    Ok(read)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::TcpListener;
+  use std::sync::mpsc;
+
+  fn request(text: &str) -> Request {
+    let mut request = Request::read_from(&mut text.as_bytes()).unwrap();
+    request.peer = Some("192.0.2.7:51000".parse().unwrap());
+    request
+  }
+
+  // an upstream that answers one connection per canned response, sending back the request heads
+  // it got
+  fn upstream(responses: Vec<&'static str>) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      for response in responses {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+          reader.read_line(&mut head).unwrap();
+        }
+        sender.send(head).unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+    (addr, receiver)
+  }
+
+  fn body(response: Response) -> String {
+    String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+  }
+
+  #[test]
+  fn prefixes() {
+    let proxy = Proxy::new("/api/", &["127.0.0.1:1"]).strip_prefix(true);
+    assert!(proxy.matches("/api") && proxy.matches("/api/users"));
+    assert!(!proxy.matches("/apiary") && !proxy.matches("/"));
+    assert_eq!(proxy.upstream_target("/api/users?page=2"), "/users?page=2");
+    assert_eq!(proxy.upstream_target("/api?x"), "/?x");
+    assert!(Proxy::new("/", &["127.0.0.1:1"]).matches("/anything"));
+  }
+
+  #[test]
+  fn forwards_and_rewrites_headers() {
+    let (addr, heads) = upstream(vec!["HTTP/1.1 201 Created\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"]);
+    let proxy = Proxy::new("/api", &[addr.as_str()]);
+    let response = proxy.forward(&request(
+      "POST /api/things HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\n\
+       X-Forwarded-For: 10.0.0.1\r\nContent-Length: 2\r\n\r\nhi"));
+
+    assert_eq!(response.status, 201);
+    assert!(!response.headers.contains("Connection"));
+    assert_eq!(body(response), "hello");
+
+    let head = heads.recv().unwrap();
+    assert!(head.starts_with("POST /api/things HTTP/1.1\r\n"));
+    assert!(head.contains(&format!("Host: {}\r\n", addr)));
+    assert!(head.contains("X-Forwarded-Host: example.com\r\n"));
+    assert!(head.contains("X-Forwarded-For: 10.0.0.1, 192.0.2.7\r\n"));
+    assert!(head.contains("Connection: close\r\n"));
+    assert!(!head.contains("X-Secret")); // named as hop-by-hop in Connection
+  }
+
+  #[test]
+  fn streams_chunked_responses() {
+    let (addr, _heads) = upstream(vec![
+      "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: t\r\n\r\n",
+    ]);
+    let response = Proxy::new("/", &[addr.as_str()]).forward(&request("GET / HTTP/1.1\r\n\r\n"));
+    assert_eq!(response.body.len(), None); // the server will chunk it again on the way out
+    assert!(!response.headers.contains("Transfer-Encoding"));
+    assert_eq!(body(response), "hello, world");
+  }
+
+  #[test]
+  fn round_robin_and_failover() {
+    let ok = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n";
+    let (first, first_heads) = upstream(vec![ok]);
+    let (second, second_heads) = upstream(vec![ok, ok]);
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string(); // closed once dropped
+    let proxy = Proxy::new("/", &[first.as_str(), dead.as_str(), second.as_str()]);
+
+    // first's turn, then dead's (which fails over to second), then second's own
+    for _ in 0..3 {
+      assert_eq!(proxy.forward(&request("GET / HTTP/1.1\r\n\r\n")).status, 200);
+    }
+    assert_eq!(first_heads.try_iter().count(), 1);
+    assert_eq!(second_heads.try_iter().count(), 2);
+
+    let nobody = Proxy::new("/", &[dead.as_str()]);
+    assert_eq!(nobody.forward(&request("GET / HTTP/1.1\r\n\r\n")).status, 502);
+  }
+
+  #[test]
+  fn health_checks_skip_down_upstreams() {
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let proxy = Proxy::new("/", &[dead.as_str(), "127.0.0.1:2"]).health_check("/health", Duration::from_secs(60));
+    // the first round of checks runs as soon as the thread starts
+    for _ in 0..100 {
+      if proxy.upstreams.list.iter().all(|upstream| !upstream.healthy.load(Ordering::SeqCst)) {
+        break;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert!(proxy.upstreams.list.iter().all(|upstream| !upstream.healthy.load(Ordering::SeqCst)));
+  }
+}
@@ -0,0 +1,67 @@
+/*
+the Range request header, for fetching part of a file. we only handle a single byte range, e.g.
+  bytes=0-499   the first 500 bytes
+  bytes=500-    everything from byte 500 on
+  bytes=-500    the last 500 bytes
+anything else (several ranges, other units) is ignored and the whole file is sent, which the spec
+allows
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+  Full,                 // no usable range, send everything
+  Partial(u64, u64),    // first and last byte to send, inclusive
+  Unsatisfiable,        // the range starts past the end, answer 416
+}
+
+/// Works out which bytes of a `length` byte body the `Range` header `header` asks for.
+pub fn parse(header: Option<&str>, length: u64) -> ByteRange {
+  let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+    Some(spec) if !spec.contains(',') => spec.trim(),
+    _ => return ByteRange::Full,
+  };
+  let (first, last) = match spec.find('-') {
+    Some(index) => (&spec[..index], &spec[index + 1..]),
+    None => return ByteRange::Full,
+  };
+
+  match (first.parse::<u64>(), last.parse::<u64>()) {
+    (Ok(first), _) if first >= length => ByteRange::Unsatisfiable,
+    (Ok(first), Ok(last)) if first <= last => ByteRange::Partial(first, last.min(length - 1)),
+    (Ok(first), Err(_)) if last.is_empty() => ByteRange::Partial(first, length - 1),
+    (Err(_), Ok(0)) if first.is_empty() => ByteRange::Unsatisfiable,
+    (Err(_), Ok(suffix)) if first.is_empty() => {
+      if length == 0 {
+        ByteRange::Unsatisfiable
+      } else {
+        ByteRange::Partial(length.saturating_sub(suffix), length - 1)
+      }
+    }
+    _ => ByteRange::Full, // syntactically broken ranges are ignored
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ranges() {
+    assert_eq!(parse(None, 100), ByteRange::Full);
+    assert_eq!(parse(Some("bytes=0-9"), 100), ByteRange::Partial(0, 9));
+    assert_eq!(parse(Some("bytes=90-200"), 100), ByteRange::Partial(90, 99));
+    assert_eq!(parse(Some("bytes=50-"), 100), ByteRange::Partial(50, 99));
+    assert_eq!(parse(Some("bytes=-10"), 100), ByteRange::Partial(90, 99));
+    assert_eq!(parse(Some("bytes=-500"), 100), ByteRange::Partial(0, 99));
+    assert_eq!(parse(Some("bytes=100-"), 100), ByteRange::Unsatisfiable);
+    assert_eq!(parse(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+  }
+
+  #[test]
+  fn ignores_what_we_dont_handle() {
+    assert_eq!(parse(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+    assert_eq!(parse(Some("lines=0-1"), 100), ByteRange::Full);
+    assert_eq!(parse(Some("bytes=9-2"), 100), ByteRange::Full);
+    assert_eq!(parse(Some("bytes=x-y"), 100), ByteRange::Full);
+  }
+}
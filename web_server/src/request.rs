@@ -0,0 +1,426 @@
+/*
+parsing of HTTP/1.1 requests straight off of a stream.
+instead of reading one fixed sized buffer we pull a line at a time out of a BufRead, so requests
+can be any length (up to our limits) and arrive in as many tcp packets as the client likes
+*/
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use serde::de::DeserializeOwned;
+
+use crate::headers::Headers;
+use crate::json::{self, JsonError};
+
+/// Largest request line plus headers `read_from` is willing to buffer.
+pub const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// Largest `Content-Length` body `read_from` is willing to buffer.
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+  pub method: String,  // GET, POST, etc. always upper case
+  pub target: String,  // the raw request target, including any query string
+  pub version: String, // HTTP/1.0 or HTTP/1.1
+  pub headers: Headers,
+  pub body: Vec<u8>,
+  pub params: HashMap<String, String>, // filled in by the Router from ":name" and "*name" segments
+  pub received: Instant, // when we finished reading the request off of the connection
+  pub peer: Option<SocketAddr>, // who sent it, filled in by the server
+}
+
+impl Request {
+  /// Reads and parses a single request from `reader`.
+  ///
+  /// Only the bytes belonging to this request are consumed, so calling this again on the same
+  /// reader returns the next request on the connection.
+  ///
+  /// # Errors
+  ///
+  /// Returns `ParseError::ConnectionClosed` if the stream ends before any bytes arrive, and one
+  /// of the other `ParseError` variants if the request is malformed or too large.
+  pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+    let mut request = Request::read_head(reader, MAX_HEADER_BYTES)?;
+    request.read_body(reader, MAX_BODY_BYTES)?;
+    Ok(request)
+  }
+
+  /// Reads just the request line and headers, at most `max_header_bytes` of them, leaving the
+  /// body on the reader for `read_body`. Reading the two separately lets a server give each its
+  /// own deadline.
+  pub fn read_head<R: BufRead>(reader: &mut R, max_header_bytes: usize) -> Result<Request, ParseError> {
+    let mut budget = max_header_bytes; // shared between the request line and all headers
+
+    // clients are allowed to send stray blank lines between requests, so skip over them
+    let request_line = loop {
+      match read_line(reader, &mut budget)? {
+        None => return Err(ParseError::ConnectionClosed),
+        Some(line) if line.is_empty() => continue,
+        Some(line) => break line,
+      }
+    };
+    let (method, target, version) = parse_request_line(&request_line)?;
+
+    let mut headers = Headers::new();
+    loop {
+      let line = match read_line(reader, &mut budget)? {
+        None => return Err(ParseError::UnexpectedEof),
+        Some(line) => line,
+      };
+      if line.is_empty() { // a blank line marks the end of the headers
+        break;
+      }
+      let (name, value) = parse_header(&line)?;
+      headers.append(name, value);
+    }
+
+    Ok(Request {
+      method, target, version, headers,
+      body: Vec::new(),
+      params: HashMap::new(),
+      received: Instant::now(),
+      peer: None,
+    })
+  }
+
+  /// Reads the body announced by the headers, refusing ones longer than `max_body_bytes`.
+  pub fn read_body<R: BufRead>(&mut self, reader: &mut R, max_body_bytes: usize) -> Result<(), ParseError> {
+    self.body = read_body(reader, &self.headers, max_body_bytes)?;
+    self.received = Instant::now();
+    Ok(())
+  }
+
+  /// The path part of the target, without any query string.
+  pub fn path(&self) -> &str {
+    match self.target.find('?') {
+      Some(index) => &self.target[..index],
+      None => &self.target,
+    }
+  }
+
+  /// The raw query string after the `?`, if there is one.
+  pub fn query(&self) -> Option<&str> {
+    self.target.find('?').map(|index| &self.target[index + 1..])
+  }
+
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.get(name)
+  }
+
+  /// Whether the client wants to reuse the connection for more requests. HTTP/1.1 keeps
+  /// connections open unless told otherwise, HTTP/1.0 closes them unless told otherwise.
+  pub fn keep_alive(&self) -> bool {
+    if self.version == "HTTP/1.0" {
+      self.headers.has_token("Connection", "keep-alive")
+    } else {
+      !self.headers.has_token("Connection", "close")
+    }
+  }
+
+  /// A path parameter captured by the route that matched this request.
+  pub fn param(&self, name: &str) -> Option<&str> {
+    self.params.get(name).map(|value| value.as_str())
+  }
+
+  /// Parses the body as json into a `T`.
+  ///
+  /// # Errors
+  ///
+  /// Fails if the Content-Type isn't json, or the body isn't valid json for a `T`. The error's
+  /// `into_response` is a fine thing to send back.
+  pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+    json::parse(self)
+  }
+}
+
+/// Decodes `%XX` escapes in a url path. Returns `None` on bad escapes, invalid utf-8 or a
+/// decoded NUL byte.
+pub fn percent_decode(input: &str) -> Option<String> {
+  let bytes = input.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      let hex = input.get(i + 1..i + 3)?;
+      if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None; // from_str_radix would take "+f" as well
+      }
+      decoded.push(u8::from_str_radix(hex, 16).ok()?);
+      i += 3;
+    } else {
+      decoded.push(bytes[i]);
+      i += 1;
+    }
+  }
+
+  if decoded.contains(&0) {
+    return None;
+  }
+  String::from_utf8(decoded).ok()
+}
+
+// reads one CRLF (or bare LF) terminated line, without the line ending.
+// returns None if the stream was already at its end
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+  let mut line = Vec::new();
+  // take() stops us from buffering forever when a client never sends a newline
+  let read = reader.by_ref().take(*budget as u64).read_until(b'\n', &mut line)?;
+  *budget -= read;
+
+  if read == 0 {
+    return if *budget == 0 { Err(ParseError::HeadersTooLarge) } else { Ok(None) };
+  }
+  if line.last() != Some(&b'\n') {
+    return if *budget == 0 { Err(ParseError::HeadersTooLarge) } else { Err(ParseError::UnexpectedEof) };
+  }
+
+  line.pop();
+  if line.last() == Some(&b'\r') {
+    line.pop();
+  }
+  match String::from_utf8(line) {
+    Ok(line) => Ok(Some(line)),
+    Err(_) => Err(ParseError::BadEncoding),
+  }
+}
+
+fn parse_request_line(line: &str) -> Result<(String, String, String), ParseError> {
+  let parts: Vec<&str> = line.split(' ').collect();
+  if parts.len() != 3 {
+    return Err(ParseError::BadRequestLine);
+  }
+  let (method, target, version) = (parts[0], parts[1], parts[2]);
+
+  if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+    return Err(ParseError::BadRequestLine);
+  }
+  if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+    return Err(ParseError::BadRequestLine);
+  }
+  match version {
+    "HTTP/1.1" | "HTTP/1.0" => {}
+    _ if version.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+    _ => return Err(ParseError::BadRequestLine),
+  }
+
+  Ok((method.to_string(), target.to_string(), version.to_string()))
+}
+
+fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+  // lines starting with whitespace are the obsolete multi-line header folding. just refuse them
+  if line.starts_with(' ') || line.starts_with('\t') {
+    return Err(ParseError::BadHeader);
+  }
+  let colon = line.find(':').ok_or(ParseError::BadHeader)?;
+  let name = &line[..colon];
+  if name.is_empty() || name.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+    return Err(ParseError::BadHeader);
+  }
+  Ok((name, line[colon + 1..].trim()))
+}
+
+fn read_body<R: BufRead>(reader: &mut R, headers: &Headers, max_body_bytes: usize) -> Result<Vec<u8>, ParseError> {
+  if headers.contains("Transfer-Encoding") {
+    return Err(ParseError::UnsupportedTransferEncoding);
+  }
+
+  // repeated Content-Length headers are only ok if they all agree
+  let mut length = None;
+  for value in headers.get_all("Content-Length") {
+    // digits and nothing else. parse() would also take "+5", and if a proxy in front of us reads
+    // the length differently, the rest of the body gets taken for a request of its own
+    if !value.bytes().all(|b| b.is_ascii_digit()) {
+      return Err(ParseError::BadContentLength);
+    }
+    let parsed = value.parse::<usize>().map_err(|_| ParseError::BadContentLength)?;
+    if length.is_some() && length != Some(parsed) {
+      return Err(ParseError::BadContentLength);
+    }
+    length = Some(parsed);
+  }
+
+  let length = length.unwrap_or(0);
+  if length > max_body_bytes {
+    return Err(ParseError::BodyTooLarge);
+  }
+
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body).map_err(|e| match e.kind() {
+    io::ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+    _ => ParseError::from(e),
+  })?;
+  Ok(body)
+}
+
+/// Everything that can go wrong while reading a request.
+#[derive(Debug)]
+pub enum ParseError {
+  Io(io::Error),
+  ConnectionClosed, // the client hung up before sending anything, not really an error
+  UnexpectedEof,    // the client hung up part way through a request
+  BadRequestLine,
+  BadHeader,
+  BadEncoding,
+  BadContentLength,
+  HeadersTooLarge,
+  BodyTooLarge,
+  UnsupportedVersion,
+  UnsupportedTransferEncoding,
+  TimedOut, // the client took too long to send the request
+}
+
+impl ParseError {
+  /// The status code a server should answer this error with.
+  pub fn status_code(&self) -> u16 {
+    match self {
+      ParseError::HeadersTooLarge => 431,
+      ParseError::BodyTooLarge => 413,
+      ParseError::UnsupportedVersion => 505,
+      ParseError::UnsupportedTransferEncoding => 501,
+      ParseError::TimedOut => 408,
+      _ => 400,
+    }
+  }
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ParseError::Io(e) => write!(f, "i/o error while reading request: {}", e),
+      ParseError::ConnectionClosed => write!(f, "connection closed"),
+      ParseError::UnexpectedEof => write!(f, "connection closed in the middle of a request"),
+      ParseError::BadRequestLine => write!(f, "malformed request line"),
+      ParseError::BadHeader => write!(f, "malformed header"),
+      ParseError::BadEncoding => write!(f, "request head is not valid utf-8"),
+      ParseError::BadContentLength => write!(f, "invalid Content-Length"),
+      ParseError::HeadersTooLarge => write!(f, "request headers are too large"),
+      ParseError::BodyTooLarge => write!(f, "request body is too large"),
+      ParseError::UnsupportedVersion => write!(f, "unsupported http version"),
+      ParseError::UnsupportedTransferEncoding => write!(f, "Transfer-Encoding is not supported"),
+      ParseError::TimedOut => write!(f, "timed out waiting for the request"),
+    }
+  }
+}
+
+impl Error for ParseError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ParseError::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for ParseError {
+  fn from(error: io::Error) -> ParseError {
+    match error.kind() {
+      // what a read timeout looks like, depending on the platform
+      io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::TimedOut,
+      _ => ParseError::Io(error),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(raw: &str) -> Result<Request, ParseError> {
+    Request::read_from(&mut raw.as_bytes()) // &[u8] implements BufRead
+  }
+
+  #[test]
+  fn simple_get() {
+    let request = parse("GET /index.html?name=ferris HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.target, "/index.html?name=ferris");
+    assert_eq!(request.path(), "/index.html");
+    assert_eq!(request.query(), Some("name=ferris"));
+    assert_eq!(request.version, "HTTP/1.1");
+    assert_eq!(request.header("host"), Some("localhost")); // lookups ignore case
+    assert!(request.body.is_empty());
+    assert!(request.keep_alive());
+  }
+
+  #[test]
+  fn connection_header() {
+    assert!(!parse("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap().keep_alive());
+    assert!(!parse("GET / HTTP/1.0\r\n\r\n").unwrap().keep_alive());
+    assert!(parse("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().keep_alive());
+  }
+
+  #[test]
+  fn body_uses_content_length() {
+    let mut raw = "POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n".as_bytes();
+    let first = Request::read_from(&mut raw).unwrap();
+    assert_eq!(first.body, b"hello");
+
+    // the next request on the stream is left untouched
+    let second = Request::read_from(&mut raw).unwrap();
+    assert_eq!(second.target, "/");
+    assert!(matches!(Request::read_from(&mut raw), Err(ParseError::ConnectionClosed)));
+  }
+
+  #[test]
+  fn percent_escapes() {
+    assert_eq!(percent_decode("/a%20b/%C3%A9").as_deref(), Some("/a b/é"));
+    assert_eq!(percent_decode("/100%"), None);
+    assert_eq!(percent_decode("/%zz"), None);
+    assert_eq!(percent_decode("/%+f"), None);
+    assert_eq!(percent_decode("/%00"), None);
+  }
+
+  #[test]
+  fn malformed_requests() {
+    assert!(matches!(parse("GET /\r\n\r\n"), Err(ParseError::BadRequestLine)));
+    assert!(matches!(parse("get / HTTP/1.1\r\n\r\n"), Err(ParseError::BadRequestLine)));
+    assert!(matches!(parse("GET / HTTP/2.0\r\n\r\n"), Err(ParseError::UnsupportedVersion)));
+    assert!(matches!(parse("GET / HTTP/1.1\r\nno colon\r\n\r\n"), Err(ParseError::BadHeader)));
+    assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\n"), Err(ParseError::UnexpectedEof)));
+    assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n"),
+                     Err(ParseError::BadContentLength)));
+    assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello"),
+                     Err(ParseError::BadContentLength)));
+    assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+                     Err(ParseError::UnexpectedEof)));
+    assert_eq!(parse("GET / HTTP/1.1\r\nBad Header: x\r\n\r\n").unwrap_err().status_code(), 400);
+  }
+
+  #[test]
+  fn oversized_headers() {
+    let raw = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_HEADER_BYTES));
+    let error = parse(&raw).unwrap_err();
+    assert!(matches!(error, ParseError::HeadersTooLarge));
+    assert_eq!(error.status_code(), 431);
+  }
+
+  #[test]
+  fn head_and_body_separately() {
+    let mut reader = &b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"[..];
+    let mut request = Request::read_head(&mut reader, 64).unwrap();
+    assert!(request.body.is_empty());
+    assert_eq!(reader, b"hello"); // the body is left for later
+    request.read_body(&mut reader, 5).unwrap();
+    assert_eq!(request.body, b"hello");
+
+    let mut reader = &b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"[..];
+    assert!(matches!(Request::read_head(&mut reader, 16), Err(ParseError::HeadersTooLarge)));
+    let mut reader = &b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"[..];
+    let mut request = Request::read_head(&mut reader, 64).unwrap();
+    assert!(matches!(request.read_body(&mut reader, 4), Err(ParseError::BodyTooLarge)));
+  }
+
+  #[test]
+  fn timeouts() {
+    let error = ParseError::from(io::Error::from(io::ErrorKind::WouldBlock));
+    assert!(matches!(error, ParseError::TimedOut));
+    assert_eq!(error.status_code(), 408);
+  }
+}
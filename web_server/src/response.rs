@@ -0,0 +1,282 @@
+/*
+an http response that knows how to write itself onto a stream.
+
+the body is either bytes we already have in memory, or a reader we copy from as we write, so a big
+file or generated output never has to be held in memory all at once. a streamed body whose length
+isn't known up front is sent with chunked transfer encoding.
+
+a 101 Switching Protocols response can carry an Upgrade, which takes the connection over once the
+response has been sent (that's how websockets start)
+*/
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use serde::Serialize;
+
+use crate::headers::Headers;
+use crate::json;
+use crate::log::Level;
+use crate::status::reason_phrase;
+use crate::transport::Socket;
+
+// how much of a streamed body we read before writing it out as one chunk
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct Response {
+  pub status: u16,
+  pub headers: Headers,
+  pub body: Body,
+  pub upgrade: Option<Upgrade>, // what runs on the connection after a 101
+}
+
+/// Takes over a connection after its 101 response has been written, speaking whatever protocol
+/// the response switched to. It runs on the pool worker the connection was on.
+pub struct Upgrade(Box<dyn FnOnce(Box<dyn Socket>) + Send>);
+
+impl Upgrade {
+  pub fn new<F: FnOnce(Box<dyn Socket>) + Send + 'static>(handler: F) -> Upgrade {
+    Upgrade(Box::new(handler))
+  }
+
+  pub fn run(self, socket: Box<dyn Socket>) {
+    (self.0)(socket)
+  }
+}
+
+impl fmt::Debug for Upgrade {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Upgrade")
+  }
+}
+
+pub enum Body {
+  Bytes(Vec<u8>),
+  Stream {
+    reader: Box<dyn Read + Send>,
+    length: Option<u64>, // None if we won't know until the reader runs dry
+  },
+}
+
+impl Body {
+  /// The body's length in bytes, if it's known before sending it.
+  pub fn len(&self) -> Option<u64> {
+    match self {
+      Body::Bytes(bytes) => Some(bytes.len() as u64),
+      Body::Stream { length, .. } => *length,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == Some(0)
+  }
+
+  /// The body, if it's held in memory rather than streamed.
+  pub fn as_bytes(&self) -> Option<&[u8]> {
+    match self {
+      Body::Bytes(bytes) => Some(bytes),
+      Body::Stream { .. } => None,
+    }
+  }
+
+  /// Reads the whole body into memory.
+  pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+    match self {
+      Body::Bytes(bytes) => Ok(bytes),
+      Body::Stream { mut reader, .. } => {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+      }
+    }
+  }
+}
+
+impl Default for Body {
+  fn default() -> Body {
+    Body::Bytes(Vec::new())
+  }
+}
+
+impl fmt::Debug for Body {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+      Body::Stream { length, .. } => write!(f, "Stream {{ length: {:?} }}", length),
+    }
+  }
+}
+
+impl Response {
+  /// Creates an empty response with the given status code.
+  pub fn new(status: u16) -> Response {
+    Response { status, headers: Headers::new(), body: Body::default(), upgrade: None }
+  }
+
+  /// A 200 response with a plain text body.
+  pub fn text<B: Into<Vec<u8>>>(body: B) -> Response {
+    Response::new(200)
+      .with_header("Content-Type", "text/plain; charset=utf-8")
+      .with_body(body)
+  }
+
+  /// A 200 response with an html body.
+  pub fn html<B: Into<Vec<u8>>>(body: B) -> Response {
+    Response::new(200)
+      .with_header("Content-Type", "text/html; charset=utf-8")
+      .with_body(body)
+  }
+
+  /// A 200 response with `value` serialized as its json body, or a 500 if it can't be.
+  pub fn json<T: Serialize + ?Sized>(value: &T) -> Response {
+    match serde_json::to_vec(value) {
+      Ok(body) => Response::new(200).with_header("Content-Type", json::CONTENT_TYPE).with_body(body),
+      Err(e) => {
+        log_event!(Level::Error, "Couldn't serialize a json response: {}", e);
+        Response::new(500)
+      }
+    }
+  }
+
+  /// A 302 redirect to `location`.
+  pub fn redirect(location: &str) -> Response {
+    Response::new(302).with_header("Location", location)
+  }
+
+  pub fn with_status(mut self, status: u16) -> Response {
+    self.status = status;
+    self
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Response {
+    self.headers.set(name, value);
+    self
+  }
+
+  pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+    self.body = Body::Bytes(body.into());
+    self
+  }
+
+  /// Streams the body out of `reader` as the response is written. `length` must be exactly how
+  /// many bytes the reader will produce, or `None` to send it chunked.
+  pub fn with_reader<R: Read + Send + 'static>(mut self, reader: R, length: Option<u64>) -> Response {
+    self.body = Body::Stream { reader: Box::new(reader), length };
+    self
+  }
+
+  /// Switches the connection over to `handler` once this response has been sent. Only means
+  /// anything on a 101 response.
+  pub fn with_upgrade<F: FnOnce(Box<dyn Socket>) + Send + 'static>(mut self, handler: F) -> Response {
+    self.upgrade = Some(Upgrade::new(handler));
+    self
+  }
+
+  /// Drops the body but keeps the headers describing it, which is how a HEAD request is answered.
+  pub fn without_body(mut self) -> Response {
+    if !self.headers.contains("Content-Length") && !self.headers.contains("Transfer-Encoding") {
+      match self.body.len() {
+        Some(length) => self.headers.set("Content-Length", &length.to_string()),
+        None => self.headers.set("Transfer-Encoding", "chunked"),
+      }
+    }
+    self.body = Body::default();
+    self
+  }
+
+  /// Writes the status line, headers and body to `writer`, returning how many body bytes were
+  /// sent. A streamed body is used up in the process.
+  ///
+  /// Unless the headers already say how the body is framed, a `Content-Length` header is added
+  /// for a body of known length. A streamed body is chunk encoded if `Transfer-Encoding: chunked`
+  /// is set, otherwise it is written as is and the connection has to be closed to end it.
+  /// Setting `Content-Length` yourself with an empty body is how HEAD responses advertise the
+  /// length of a body they don't send.
+  pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+    // 1xx, 204 and 304 responses never have a body, so there's nothing to give a length for
+    let bodyless = self.status < 200 || self.status == 204 || self.status == 304;
+    if !bodyless && !self.headers.contains("Content-Length") && !self.headers.contains("Transfer-Encoding") {
+      if let Some(length) = self.body.len() {
+        head.push_str(&format!("Content-Length: {}\r\n", length));
+      }
+    }
+    head.push_str(&self.headers.to_string());
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())?;
+
+    let chunked = self.headers.has_token("Transfer-Encoding", "chunked");
+    let written = match std::mem::take(&mut self.body) {
+      Body::Bytes(bytes) => {
+        writer.write_all(&bytes)?;
+        bytes.len() as u64
+      }
+      Body::Stream { mut reader, .. } if chunked => write_chunked(&mut reader, writer)?,
+      Body::Stream { mut reader, .. } => io::copy(&mut reader, writer)?,
+    };
+    writer.flush()?;
+    Ok(written)
+  }
+}
+
+// copies `reader` to `writer` as a series of "<size in hex>\r\n<data>\r\n" chunks, ending with
+// an empty one
+fn write_chunked<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+  let mut buffer = vec![0; CHUNK_SIZE];
+  let mut total = 0;
+  loop {
+    let read = match reader.read(&mut buffer) {
+      Ok(0) => break,
+      Ok(read) => read,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e),
+    };
+    write!(writer, "{:x}\r\n", read)?;
+    writer.write_all(&buffer[..read])?;
+    writer.write_all(b"\r\n")?;
+    total += read as u64;
+  }
+  writer.write_all(b"0\r\n\r\n")?;
+  Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn written(mut response: Response) -> String {
+    let mut out = Vec::new();
+    response.write_to(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn known_length() {
+    let response = Response::new(200).with_reader(&b"hello"[..], Some(5));
+    assert_eq!(written(response), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+  }
+
+  #[test]
+  fn chunked() {
+    let response = Response::new(200)
+      .with_header("Transfer-Encoding", "chunked")
+      .with_reader(io::repeat(b'a').take(CHUNK_SIZE as u64 + 3), None);
+    let text = written(response);
+    let body = &text[text.find("\r\n\r\n").unwrap() + 4..];
+    assert!(body.starts_with("4000\r\naaaa"));
+    assert!(body.ends_with("aaaa\r\n3\r\naaa\r\n0\r\n\r\n"));
+  }
+
+  #[test]
+  fn head_keeps_framing() {
+    let response = Response::text("hello").without_body();
+    assert_eq!(response.headers.get("Content-Length"), Some("5"));
+    assert!(response.body.is_empty());
+
+    let response = Response::new(200).with_reader(io::empty(), None).without_body();
+    assert_eq!(response.headers.get("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(written(response), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+  }
+}
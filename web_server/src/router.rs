@@ -0,0 +1,288 @@
+// a small router that picks a handler closure based on the request method and path.
+//
+// patterns are split on '/' and each segment is one of:
+//   literal   "/users"   must match exactly
+//   parameter "/:id"     matches any one segment and stores it as request.param("id")
+//   wildcard  "/*path"   matches the rest of the path (possibly empty), only allowed last
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::request::{percent_decode, Request};
+use crate::response::Response;
+use crate::websocket::{self, WebSocket};
+
+/// A request handler. Handlers are shared between all the pool's threads, hence Send + Sync.
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+pub struct Router {
+  routes: Vec<Route>,
+  not_found: Handler,
+  method_not_allowed: Handler,
+}
+
+struct Route {
+  method: String,
+  pattern: Vec<Segment>,
+  handler: Handler,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+  Literal(String),
+  Param(String),
+  Wildcard(String),
+}
+
+impl Router {
+  pub fn new() -> Router {
+    Router {
+      routes: Vec::new(),
+      not_found: Box::new(|_| Response::new(404).with_body("404 Not Found\n")),
+      method_not_allowed: Box::new(|_| Response::new(405).with_body("405 Method Not Allowed\n")),
+    }
+  }
+
+  /// Registers `handler` for requests with the given method whose path matches `pattern`.
+  ///
+  /// Routes are tried in the order they were added and the first match wins.
+  ///
+  /// # Panics
+  ///
+  /// Panics if a wildcard segment is not the last segment of `pattern`.
+  pub fn route<F>(&mut self, method: &str, pattern: &str, handler: F) -> &mut Router
+    where
+      F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+      self.routes.push(Route {
+        method: method.to_ascii_uppercase(),
+        pattern: parse_pattern(pattern),
+        handler: Box::new(handler),
+      });
+      self
+    }
+
+  pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+      self.route("GET", pattern, handler)
+    }
+
+  pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+      self.route("POST", pattern, handler)
+    }
+
+  pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+      self.route("PUT", pattern, handler)
+    }
+
+  pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+      self.route("DELETE", pattern, handler)
+    }
+
+  /// Registers a websocket endpoint at `pattern`. Once the handshake is done `handler` gets the
+  /// request and the open WebSocket, and keeps the connection's pool worker until it returns.
+  pub fn websocket<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where F: Fn(&Request, WebSocket) + Send + Sync + 'static {
+      let handler = Arc::new(handler);
+      self.route("GET", pattern, move |request| {
+        let (handler, request_copy) = (Arc::clone(&handler), request.clone()); // the upgrade outlives this call
+        websocket::accept(request, move |ws| handler(&request_copy, ws))
+      })
+    }
+
+  /// Replaces the handler used when no route matches the path.
+  pub fn not_found<F>(&mut self, handler: F) -> &mut Router
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+      self.not_found = Box::new(handler);
+      self
+    }
+
+  /// Replaces the handler used when a route matches the path but not the method.
+  /// The router fills in the `Allow` header if the handler doesn't.
+  pub fn method_not_allowed<F>(&mut self, handler: F) -> &mut Router
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+      self.method_not_allowed = Box::new(handler);
+      self
+    }
+
+  /// Finds the handler for `request` and runs it.
+  pub fn handle(&self, mut request: Request) -> Response {
+    let mut allowed: Vec<&str> = Vec::new();
+    let mut head_fallback = None; // a GET route we can use for HEAD if nothing else matches
+
+    for route in &self.routes {
+      let params = match match_pattern(&route.pattern, request.path()) {
+        Some(params) => params,
+        None => continue,
+      };
+
+      if route.method == request.method {
+        request.params = params;
+        return (route.handler)(&request);
+      }
+      if route.method == "GET" && request.method == "HEAD" && head_fallback.is_none() {
+        head_fallback = Some((route, params));
+      }
+      if !allowed.contains(&route.method.as_str()) {
+        allowed.push(&route.method);
+      }
+    }
+
+    if let Some((route, params)) = head_fallback {
+      request.params = params;
+      return (route.handler)(&request).without_body();
+    }
+
+    if allowed.is_empty() {
+      return (self.not_found)(&request);
+    }
+
+    let response = (self.method_not_allowed)(&request);
+    if response.headers.contains("Allow") {
+      return response;
+    }
+    if allowed.contains(&"GET") && !allowed.contains(&"HEAD") {
+      allowed.push("HEAD");
+    }
+    response.with_header("Allow", &allowed.join(", "))
+  }
+}
+
+impl Default for Router {
+  fn default() -> Router {
+    Router::new()
+  }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+  let segments: Vec<Segment> = pattern.split('/')
+    .filter(|segment| !segment.is_empty())
+    .map(|segment| {
+      if let Some(name) = segment.strip_prefix(':') {
+        Segment::Param(name.to_string())
+      } else if let Some(name) = segment.strip_prefix('*') {
+        Segment::Wildcard(name.to_string())
+      } else {
+        Segment::Literal(segment.to_string())
+      }
+    })
+    .collect();
+
+  let wildcards = segments.iter().filter(|segment| matches!(segment, Segment::Wildcard(_))).count();
+  if wildcards > 1 || (wildcards == 1 && !matches!(segments.last(), Some(Segment::Wildcard(_)))) {
+    panic!("route pattern {:?} may only have a wildcard as its last segment", pattern);
+  }
+  segments
+}
+
+// returns the captured parameters if `path` matches `pattern`
+fn match_pattern(pattern: &[Segment], path: &str) -> Option<HashMap<String, String>> {
+  let mut params = HashMap::new();
+  let mut parts = path.split('/').filter(|part| !part.is_empty());
+
+  for segment in pattern {
+    match segment {
+      Segment::Literal(literal) => {
+        if percent_decode(parts.next()?)? != *literal {
+          return None;
+        }
+      }
+      Segment::Param(name) => {
+        params.insert(name.clone(), percent_decode(parts.next()?)?);
+      }
+      Segment::Wildcard(name) => {
+        let rest: Vec<&str> = parts.by_ref().collect();
+        params.insert(name.clone(), percent_decode(&rest.join("/"))?);
+      }
+    }
+  }
+
+  if parts.next().is_some() { // pattern ran out before the path did
+    return None;
+  }
+  Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::headers::Headers;
+  use std::time::Instant;
+
+  fn request(method: &str, target: &str) -> Request {
+    Request {
+      method: method.to_string(),
+      target: target.to_string(),
+      version: String::from("HTTP/1.1"),
+      headers: Headers::new(),
+      body: Vec::new(),
+      params: HashMap::new(),
+      received: Instant::now(),
+      peer: None,
+    }
+  }
+
+  fn body(response: Response) -> String {
+    String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+  }
+
+  fn test_router() -> Router {
+    let mut router = Router::new();
+    router
+      .get("/", |_| Response::text("home"))
+      .get("/users/:id", |req| Response::text(format!("user {}", req.param("id").unwrap())))
+      .delete("/users/:id", |_| Response::new(204))
+      .get("/files/*path", |req| Response::text(format!("file {}", req.param("path").unwrap())));
+    router
+  }
+
+  #[test]
+  fn matches_literals_and_params() {
+    let router = test_router();
+    assert_eq!(body(router.handle(request("GET", "/"))), "home");
+    assert_eq!(body(router.handle(request("GET", "/users/42?verbose=1"))), "user 42");
+    assert_eq!(body(router.handle(request("GET", "/users/j%20doe"))), "user j doe");
+    assert_eq!(router.handle(request("DELETE", "/users/42")).status, 204);
+    assert_eq!(router.handle(request("GET", "/users/42/extra")).status, 404);
+  }
+
+  #[test]
+  fn wildcard_takes_the_rest() {
+    let router = test_router();
+    assert_eq!(body(router.handle(request("GET", "/files/css/site.css"))), "file css/site.css");
+    assert_eq!(body(router.handle(request("GET", "/files"))), "file ");
+  }
+
+  #[test]
+  fn method_not_allowed_lists_methods() {
+    let router = test_router();
+    let response = router.handle(request("POST", "/users/1"));
+    assert_eq!(response.status, 405);
+    assert_eq!(response.headers.get("Allow"), Some("GET, DELETE, HEAD"));
+  }
+
+  #[test]
+  fn head_uses_get_route() {
+    let router = test_router();
+    let response = router.handle(request("HEAD", "/"));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.headers.get("Content-Length"), Some("4"));
+    assert!(response.body.is_empty());
+  }
+
+  #[test]
+  fn custom_not_found() {
+    let mut router = test_router();
+    router.not_found(|req| Response::new(404).with_body(format!("no {}", req.path())));
+    assert_eq!(body(router.handle(request("GET", "/nope"))), "no /nope");
+  }
+
+  #[test]
+  #[should_panic]
+  fn wildcard_must_be_last() {
+    Router::new().get("/*rest/more", |_| Response::new(200));
+  }
+}
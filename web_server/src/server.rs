@@ -0,0 +1,594 @@
+/*
+the accept loop, and reading requests off of a connection and writing back whatever the router
+answers with. a connection is kept open for more requests until the client asks us to close it,
+goes quiet for longer than the keep-alive timeout, has used up its share of requests, or the
+server is shutting down.
+
+each part of a request has its own deadline (waiting for it to start, its headers, its body) so a
+client trickling bytes in can't hold a worker indefinitely, and no one address may have more than
+a set number of connections open at once.
+
+with the `tls` feature a server can listen for https as well, on its own addresses but answering
+from the same router and pool. plain http listeners can then be told to just redirect to https
+*/
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::limits::{DeadlineStream, PeerLimiter};
+use crate::log::{self, AccessEntry, Level};
+use crate::request::{self, ParseError, Request};
+use crate::response::{Response, Upgrade};
+use crate::router::Router;
+use crate::metrics;
+use crate::middleware::{Chain, Middleware};
+use crate::pool::{ExecuteError, OverflowPolicy, PoolMonitor, Scheduler, ThreadPool};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConfig};
+use crate::transport::{Transport, Upgraded};
+
+/// Number of worker threads a Server starts with unless told otherwise.
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// How long shutdown waits for in-flight requests before giving up on them.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how often the accept loop wakes up to check whether it has been asked to shut down
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a connection may keep a worker waiting between requests.
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many requests one connection may send before we close it, so a single busy client
+/// can't hold on to a worker forever.
+pub const DEFAULT_MAX_REQUESTS: usize = 100;
+
+/// How long a client has to send the request line and headers once it has started a request.
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to send a request body once its headers are in.
+pub const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a single write of a response may block on a client that isn't reading.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+  pub keep_alive_timeout: Duration,
+  pub max_requests: usize,
+  pub header_timeout: Duration,
+  pub body_timeout: Duration,
+  pub write_timeout: Duration,
+  pub max_header_bytes: usize,
+  pub max_body_bytes: usize,
+  pub max_connections_per_ip: Option<usize>, // None for no limit
+}
+
+impl Default for ConnectionConfig {
+  fn default() -> ConnectionConfig {
+    ConnectionConfig {
+      keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+      max_requests: DEFAULT_MAX_REQUESTS,
+      header_timeout: DEFAULT_HEADER_TIMEOUT,
+      body_timeout: DEFAULT_BODY_TIMEOUT,
+      write_timeout: DEFAULT_WRITE_TIMEOUT,
+      max_header_bytes: request::MAX_HEADER_BYTES,
+      max_body_bytes: request::MAX_BODY_BYTES,
+      max_connections_per_ip: None,
+    }
+  }
+}
+
+/// A cheap, cloneable handle that tells a running Server to stop.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+  requested: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+  pub fn new() -> ShutdownHandle {
+    ShutdownHandle::default()
+  }
+
+  /// Asks the server to stop accepting connections and wind down. Safe to call more than once
+  /// and from any thread, including a signal handler thread.
+  pub fn shutdown(&self) {
+    self.requested.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_shutdown(&self) -> bool {
+    self.requested.load(Ordering::SeqCst)
+  }
+}
+
+// everything a worker needs to answer requests, shared between all of them
+struct Context {
+  router: Router,
+  middleware: Chain,
+  config: ConnectionConfig,
+  shutdown: ShutdownHandle,
+  metrics: Option<(String, PoolMonitor)>, // the path pool stats are served on, if enabled
+  peers: Arc<PeerLimiter>,
+  https_redirect: Option<u16>, // answer plain http with a redirect to this https port
+}
+
+// what connections on a listener speak
+#[derive(Clone)]
+enum Scheme {
+  Http,
+  #[cfg(feature = "tls")]
+  Https(TlsConfig),
+}
+
+struct Listener {
+  tcp: TcpListener,
+  scheme: Scheme,
+}
+
+/// An http server: one or more listeners, a router and a ThreadPool to run connections on.
+pub struct Server {
+  listeners: Vec<Listener>, // never empty
+  router: Router,
+  middleware: Chain,
+  config: ConnectionConfig,
+  shutdown: ShutdownHandle,
+  workers: usize,
+  max_workers: Option<usize>,
+  queue: Option<(usize, OverflowPolicy)>,
+  scheduler: Scheduler,
+  metrics_path: Option<String>,
+  shutdown_timeout: Duration,
+  https_redirect: Option<u16>,
+}
+
+impl Server {
+  /// Binds a listener to `addr` that hands requests to `router`.
+  pub fn bind<A: ToSocketAddrs>(addr: A, router: Router) -> io::Result<Server> {
+    let tcp = TcpListener::bind(addr)?;
+    Ok(Server::new(Listener { tcp, scheme: Scheme::Http }, router))
+  }
+
+  /// Binds a listener to `addr` that speaks https, using the certificate in `tls`.
+  #[cfg(feature = "tls")]
+  pub fn bind_tls<A: ToSocketAddrs>(addr: A, tls: TlsConfig, router: Router) -> io::Result<Server> {
+    let tcp = TcpListener::bind(addr)?;
+    Ok(Server::new(Listener { tcp, scheme: Scheme::Https(tls) }, router))
+  }
+
+  fn new(listener: Listener, router: Router) -> Server {
+    Server {
+      listeners: vec![listener],
+      router,
+      middleware: Chain::new(),
+      config: ConnectionConfig::default(),
+      shutdown: ShutdownHandle::new(),
+      workers: DEFAULT_WORKERS,
+      max_workers: None,
+      queue: None,
+      scheduler: Scheduler::default(),
+      metrics_path: None,
+      shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+      https_redirect: None,
+    }
+  }
+
+  /// Listens on `addr` as well, answering connections there from the same router and pool.
+  pub fn also_bind<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Server> {
+    self.listeners.push(Listener { tcp: TcpListener::bind(addr)?, scheme: Scheme::Http });
+    Ok(self)
+  }
+
+  /// Listens for https on `addr` as well, answering connections there from the same router and
+  /// pool.
+  #[cfg(feature = "tls")]
+  pub fn also_bind_tls<A: ToSocketAddrs>(mut self, addr: A, tls: TlsConfig) -> io::Result<Server> {
+    self.listeners.push(Listener { tcp: TcpListener::bind(addr)?, scheme: Scheme::Https(tls) });
+    Ok(self)
+  }
+
+  /// Answers every request on the plain http listeners with a redirect to the same url over
+  /// https on `port`, instead of routing it.
+  pub fn https_redirect(mut self, port: u16) -> Server {
+    self.https_redirect = Some(port);
+    self
+  }
+
+  /// Wraps every request, including ones for the metrics route, in `middleware`. Middleware
+  /// runs in the order it was added.
+  pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> Server {
+    self.middleware.add(middleware);
+    self
+  }
+
+  pub fn workers(mut self, workers: usize) -> Server {
+    self.workers = workers;
+    self
+  }
+
+  /// Lets the pool grow up to `max` workers when connections back up, shrinking back to
+  /// `workers` once things quieten down.
+  pub fn max_workers(mut self, max: usize) -> Server {
+    self.max_workers = Some(max);
+    self
+  }
+
+  /// Limits how many accepted connections may wait for a free worker. With
+  /// `OverflowPolicy::Reject` connections beyond that are answered with 503 Service Unavailable,
+  /// and with `OverflowPolicy::DropOldest` so is the one that waited longest.
+  pub fn queue_capacity(mut self, capacity: usize, policy: OverflowPolicy) -> Server {
+    self.queue = Some((capacity, policy));
+    self
+  }
+
+  /// How connections waiting for a worker are handed out, see `Scheduler`.
+  pub fn scheduler(mut self, scheduler: Scheduler) -> Server {
+    self.scheduler = scheduler;
+    self
+  }
+
+  /// Serves the ThreadPool's stats in Prometheus text format on GET requests to `path`,
+  /// ahead of anything the router has for that path.
+  pub fn metrics_route(mut self, path: &str) -> Server {
+    self.metrics_path = Some(path.to_string());
+    self
+  }
+
+  pub fn connection_config(mut self, config: ConnectionConfig) -> Server {
+    self.config = config;
+    self
+  }
+
+  pub fn shutdown_timeout(mut self, timeout: Duration) -> Server {
+    self.shutdown_timeout = timeout;
+    self
+  }
+
+  /// The address the first listener is actually bound to.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.listeners[0].tcp.local_addr()
+  }
+
+  /// The addresses all of the listeners are bound to, in the order they were bound.
+  pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+    self.listeners.iter().map(|listener| listener.tcp.local_addr()).collect()
+  }
+
+  /// Where each listener can be reached, like `http://127.0.0.1:7878`. After binding port 0 this
+  /// is how to find out which port the os picked.
+  pub fn urls(&self) -> io::Result<Vec<String>> {
+    self.listeners.iter().map(|listener| {
+      let scheme = match listener.scheme {
+        Scheme::Http => "http",
+        #[cfg(feature = "tls")]
+        Scheme::Https(_) => "https",
+      };
+      Ok(format!("{}://{}", scheme, listener.tcp.local_addr()?))
+    }).collect()
+  }
+
+  /// A handle that can stop this server from another thread once `run` has been called.
+  pub fn shutdown_handle(&self) -> ShutdownHandle {
+    self.shutdown.clone()
+  }
+
+  /// Accepts connections and hands them to the pool until the shutdown handle is triggered.
+  ///
+  /// On shutdown the listeners are closed first, then in-flight connections get up to the
+  /// shutdown timeout to finish before the pool's workers are terminated.
+  pub fn run(self) -> io::Result<()> {
+    let mut builder = ThreadPool::builder(self.workers) // our custom ThreadPool struct
+      .scheduler(self.scheduler);
+    if let Some(max) = self.max_workers {
+      builder = builder.max_workers(max);
+    }
+    if let Some((capacity, policy)) = self.queue {
+      builder = builder.queue_capacity(capacity, policy);
+    }
+    let pool = builder.build().map_err(io::Error::other)?;
+    let context = Arc::new(Context {
+      peers: PeerLimiter::new(self.config.max_connections_per_ip),
+      https_redirect: self.https_redirect,
+      router: self.router,
+      middleware: self.middleware,
+      config: self.config,
+      shutdown: self.shutdown,
+      metrics: self.metrics_path.map(|path| (path, pool.monitor())),
+    });
+    // non-blocking so the loop can notice a shutdown request instead of sitting in accept() forever,
+    // and so one quiet listener doesn't hold up the others
+    for listener in &self.listeners {
+      listener.tcp.set_nonblocking(true)?;
+    }
+
+    while !context.shutdown.is_shutdown() {
+      let mut accepted = false;
+      for listener in &self.listeners {
+        let stream = match listener.tcp.accept() {
+          Ok((stream, _)) => stream,
+          Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+          Err(e) => {
+            log_event!(Level::Error, "Failed to accept connection: {}", e); // e.g. out of file descriptors
+            continue;
+          }
+        };
+        accepted = true;
+        submit(&pool, stream, &listener.scheme, &context);
+      }
+      if !accepted {
+        thread::sleep(ACCEPT_POLL_INTERVAL);
+      }
+    }
+
+    log_event!(Level::Info, "Shutting down server");
+    drop(self.listeners); // stop accepting before we wait on the workers
+    if !pool.shutdown_timeout(self.shutdown_timeout) {
+      log_event!(Level::Warn, "Gave up waiting on in-flight requests after {:?}", self.shutdown_timeout);
+    }
+    Ok(())
+  }
+}
+
+// hands a freshly accepted connection to the pool
+fn submit(pool: &ThreadPool, mut stream: TcpStream, scheme: &Scheme, context: &Arc<Context>) {
+  if let Err(e) = stream.set_nonblocking(false) {
+    log_event!(Level::Warn, "Failed to set up connection: {}", e);
+    return;
+  }
+
+  // the permit travels with the connection and frees up the address's slot when it closes
+  let permit = match stream.peer_addr() {
+    Ok(peer) => match context.peers.acquire(peer.ip()) {
+      Some(permit) => permit,
+      None => {
+        log_event!(Level::Warn, "{} has too many connections open, turning one away", peer.ip());
+        reject(&mut stream, 429, "429 Too Many Requests\n");
+        return;
+      }
+    },
+    Err(_) => return, // already gone
+  };
+
+  let context = Arc::clone(context);
+  let scheme = scheme.clone();
+  let waiting = Waiting { stream: Some(stream), answer: matches!(scheme, Scheme::Http) };
+  let submitted = pool.execute(move || {
+    let _permit = permit;
+    let stream = waiting.take();
+    match scheme {
+      Scheme::Http => handle_connection(DeadlineStream::new(stream), &context, context.https_redirect),
+      #[cfg(feature = "tls")]
+      Scheme::Https(config) => match tls::accept(&config, stream) {
+        Ok(stream) => handle_connection(stream, &context, None),
+        Err(e) => log_event!(Level::Warn, "Failed to start tls: {}", e),
+      },
+    }
+  });
+  match submitted {
+    Ok(()) => {}
+    Err(ExecuteError::QueueFull) => log_event!(Level::Warn, "Queue is full, turning a connection away"),
+    Err(e) => log_event!(Level::Error, "Failed to dispatch connection: {}", e),
+  }
+}
+
+// a connection on its way to a worker. if the pool drops the job instead of running it, because
+// the queue was full or OverflowPolicy::DropOldest pushed it out to make room, the client is told
+// with a 503 rather than just hung up on. https clients only get the hang up, a plain text answer
+// would mean nothing to them before the handshake
+struct Waiting {
+  stream: Option<TcpStream>,
+  answer: bool,
+}
+
+impl Waiting {
+  fn take(mut self) -> TcpStream {
+    self.stream.take().unwrap()
+  }
+}
+
+impl Drop for Waiting {
+  fn drop(&mut self) {
+    if let Some(mut stream) = self.stream.take() {
+      if self.answer {
+        reject(&mut stream, 503, "503 Service Unavailable\n");
+      }
+    }
+  }
+}
+
+/// Answers requests on `stream` one after another until the connection should be closed.
+/// With `redirect` set, every request is answered with a redirect to https on that port.
+fn handle_connection<S: Transport + 'static>(stream: S, context: &Context, redirect: Option<u16>) {
+  if let Err(e) = stream.tcp().set_write_timeout(Some(context.config.write_timeout)) {
+    log_event!(Level::Warn, "Failed to set up connection: {}", e);
+    return;
+  }
+  let peer = stream.tcp().peer_addr().ok();
+  // BufReader lets the parser pull the request a line at a time instead of one fixed read.
+  // responses are written to the stream underneath it, which bypasses the buffer
+  let mut connection = BufReader::new(stream);
+  match serve(&mut connection, context, peer, redirect) {
+    Some(upgrade) => {
+      connection.get_mut().set_deadline(None); // it's up to the new protocol how long to wait
+      upgrade.run(Box::new(Upgraded(connection))); // which closes the connection when it's done
+    }
+    None => {
+      let _ = connection.get_mut().close();
+    }
+  }
+}
+
+// the request loop of handle_connection.
+//
+// pipelined requests work without any special handling: whatever the client sent after the
+// current request stays in the BufReader and is parsed on the next time around the loop
+fn serve<S: Transport>(reader: &mut BufReader<S>, context: &Context, peer: Option<SocketAddr>,
+                       redirect: Option<u16>) -> Option<Upgrade> {
+  let config = &context.config;
+  for served in 1..=config.max_requests {
+    // wait for the first byte of the next request, then give the client a fixed time to send the
+    // rest however slowly the bytes come in
+    reader.get_mut().set_deadline(Some(Instant::now() + config.keep_alive_timeout));
+    match reader.fill_buf() {
+      Ok(buf) if !buf.is_empty() => {}
+      _ => return None, // closed, reset or went quiet between requests, none of which need an answer
+    }
+    reader.get_mut().set_deadline(Some(Instant::now() + config.header_timeout));
+    let result = Request::read_head(reader, config.max_header_bytes).and_then(|mut request| {
+      reader.get_mut().set_deadline(Some(Instant::now() + config.body_timeout));
+      request.read_body(reader, config.max_body_bytes)?;
+      Ok(request)
+    });
+    let (time, started) = (SystemTime::now(), Instant::now());
+    let (request_line, response, keep_alive) = match result {
+      Ok(mut request) => {
+        request.peer = peer;
+        let keep_alive = request.keep_alive() && served < config.max_requests;
+        // the router takes the request, so hang on to what the access log needs
+        let request_line = (request.method.clone(), request.target.clone(), request.version.clone());
+        let response = match redirect {
+          Some(port) => https_redirect(&request, port),
+          None => dispatch(context, request),
+        };
+        // checked after the handler, which may have been running when the shutdown came in.
+        // finish this request, but don't wait for another
+        let mut keep_alive = keep_alive && !response.headers.has_token("Connection", "close")
+          && !context.shutdown.is_shutdown();
+        let mut response = response;
+        if response.body.len().is_none() && !response.headers.contains("Transfer-Encoding") {
+          // a body of unknown length is sent chunked. HTTP/1.0 clients don't understand that,
+          // so for them the end of the body is marked by closing the connection
+          if request_line.2 == "HTTP/1.0" {
+            keep_alive = false;
+          } else {
+            response.headers.set("Transfer-Encoding", "chunked");
+          }
+        }
+        (Some(request_line), response, keep_alive)
+      }
+      Err(ParseError::ConnectionClosed) => return None, // nothing was sent, so nothing to answer
+      Err(ParseError::TimedOut) => {
+        log_event!(Level::Warn, "Timed out reading a request from {:?}", peer);
+        (None, error_response(&ParseError::TimedOut), false)
+      }
+      Err(error) => {
+        log_event!(Level::Debug, "Bad request from {:?}: {}", peer, error);
+        (None, error_response(&error), false)
+      }
+    };
+
+    // a 101 hands the connection over to whatever the response switched it to
+    let mut response = response;
+    let upgrade = if response.status == 101 { response.upgrade.take() } else { None };
+    if upgrade.is_none() {
+      // tell the client what we decided, so it knows whether to reuse the connection
+      response.headers.set("Connection", if keep_alive { "keep-alive" } else { "close" });
+    }
+
+    let written = response.write_to(reader.get_mut());
+    let (method, target, version) = match &request_line {
+      Some((method, target, version)) => (method.as_str(), target.as_str(), version.as_str()),
+      None => ("-", "-", "-"), // we never got a request line we could make sense of
+    };
+    log::access(&AccessEntry {
+      time,
+      peer,
+      method,
+      target,
+      version,
+      status: response.status,
+      bytes: *written.as_ref().unwrap_or(&0),
+      duration: started.elapsed(),
+    });
+
+    if let Err(e) = written {
+      log_event!(Level::Debug, "Failed to write response: {}", e); // the client probably hung up, nothing to do
+      return None;
+    }
+    if upgrade.is_some() || !keep_alive {
+      return upgrade;
+    }
+  }
+  None
+}
+
+// runs the middleware, then picks who answers the request: the built-in metrics route or the router
+fn dispatch(context: &Context, request: Request) -> Response {
+  context.middleware.handle(request, |request| {
+    if let Some((path, monitor)) = &context.metrics {
+      if request.method == "GET" && request.path() == path {
+        return metrics::response(&monitor.stats());
+      }
+    }
+    context.router.handle(request)
+  })
+}
+
+// sends the client to the same url over https. GET and HEAD get the 301 everything understands,
+// other methods the 308 that tells the client to send the same method and body again
+fn https_redirect(request: &Request, port: u16) -> Response {
+  let host = match request.header("Host") {
+    Some(host) => strip_port(host),
+    None => return Response::new(400).with_body("400 Bad Request: a Host header is needed\n"),
+  };
+  let location = match port {
+    443 => format!("https://{}{}", host, request.target),
+    _ => format!("https://{}:{}{}", host, port, request.target),
+  };
+  let status = if request.method == "GET" || request.method == "HEAD" { 301 } else { 308 };
+  Response::new(status).with_header("Location", &location)
+}
+
+// "example.com:8080" -> "example.com", "[::1]:8080" -> "[::1]"
+fn strip_port(host: &str) -> &str {
+  match host.rfind(':') {
+    Some(colon) if !host[colon..].contains(']') => &host[..colon],
+    _ => host,
+  }
+}
+
+// turns a client away without reading its request. this runs on the accept loop,
+// so don't let a client that isn't reading stall us
+fn reject(stream: &mut TcpStream, status: u16, message: &str) {
+  let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+  let mut response = Response::new(status)
+    .with_header("Retry-After", "1")
+    .with_header("Connection", "close")
+    .with_body(message);
+  let _ = response.write_to(stream);
+}
+
+/// The response sent back for a request we couldn't parse.
+pub fn error_response(error: &ParseError) -> Response {
+  Response::new(error.status_code())
+    .with_header("Content-Type", "text/plain; charset=utf-8")
+    .with_header("Connection", "close")
+    .with_body(format!("{}\n", error))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(text: &str) -> Request {
+    Request::read_from(&mut text.as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn redirects_to_https() {
+    let response = https_redirect(&request("GET /a?b=c HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"), 443);
+    assert_eq!(response.status, 301);
+    assert_eq!(response.headers.get("Location"), Some("https://example.com/a?b=c"));
+
+    let response = https_redirect(&request("POST /form HTTP/1.1\r\nHost: [::1]:7878\r\n\r\n"), 7443);
+    assert_eq!(response.status, 308); // so the client sends the form again
+    assert_eq!(response.headers.get("Location"), Some("https://[::1]:7443/form"));
+
+    assert_eq!(https_redirect(&request("GET / HTTP/1.0\r\n\r\n"), 443).status, 400);
+  }
+}
@@ -0,0 +1,266 @@
+/*
+serving files out of a document root directory. files are streamed straight from disk rather
+than read into memory first, and a Range header gets back just the bytes it asks for.
+
+every file gets an ETag and Last-Modified, so a client that already has a copy can ask with
+If-None-Match or If-Modified-Since and get an empty 304 Not Modified back if it's still current
+*/
+
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::date;
+use crate::log::Level;
+use crate::mime;
+use crate::range::{self, ByteRange};
+use crate::request::{percent_decode, Request};
+use crate::response::Response;
+
+/// Name of the file served when a directory is requested.
+pub const INDEX_FILE: &str = "index.html";
+
+/// Name of the file in the document root used as the body of 404 responses, if it exists.
+pub const NOT_FOUND_FILE: &str = "404.html";
+
+pub struct StaticFiles {
+  root: PathBuf,
+  cache_control: Vec<(String, String)>, // (path prefix, Cache-Control value)
+}
+
+impl StaticFiles {
+  /// Serves the files found under `root`.
+  pub fn new<P: Into<PathBuf>>(root: P) -> StaticFiles {
+    StaticFiles { root: root.into(), cache_control: Vec::new() }
+  }
+
+  /// Sends `Cache-Control: <value>` with files whose request path starts with `prefix`. When
+  /// several prefixes match, the longest one wins.
+  pub fn cache_control(mut self, prefix: &str, value: &str) -> StaticFiles {
+    self.cache_control.push((prefix.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  /// Answers a GET or HEAD request with the file its path points at.
+  pub fn serve(&self, request: &Request) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+      return Response::new(405).with_header("Allow", "GET, HEAD");
+    }
+
+    let path = match self.resolve(request.path()) {
+      Some(path) => path,
+      None => return Response::new(403).with_body("403 Forbidden\n"),
+    };
+
+    let path = if path.is_dir() {
+      // without the trailing slash relative links in the index page would point at the parent
+      if !request.path().ends_with('/') {
+        return Response::new(301).with_header("Location", &format!("{}/", request.path()));
+      }
+      path.join(INDEX_FILE)
+    } else {
+      path
+    };
+
+    let response = match File::open(&path).and_then(|file| Ok((file.metadata()?, file))) {
+      Ok((metadata, file)) => self.file_response(request, file, &metadata, mime::from_path(&path)),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.not_found(),
+      Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => Response::new(403).with_body("403 Forbidden\n"),
+      Err(_) => Response::new(500).with_body("500 Internal Server Error\n"),
+    };
+
+    if request.method == "HEAD" { // same headers as GET, but no body
+      return response.without_body();
+    }
+    response
+  }
+
+  // the 200, 206 or 304 answer for a file we managed to open
+  fn file_response(&self, request: &Request, file: File, metadata: &Metadata, content_type: &str) -> Response {
+    let etag = etag(metadata);
+    let last_modified = last_modified(metadata);
+
+    let mut response = if not_modified(request, &etag, last_modified) {
+      Response::new(304)
+    } else if if_range_matches(request, &etag, last_modified) {
+      ranged(request.header("Range"), file, metadata.len(), content_type)
+    } else {
+      ranged(None, file, metadata.len(), content_type) // the client's partial copy is stale, send it all
+    };
+
+    if response.status == 416 {
+      return response;
+    }
+    response.headers.set("ETag", &etag);
+    if let Some(modified) = last_modified {
+      response.headers.set("Last-Modified", &date::format_http(modified));
+    }
+    let prefixes = self.cache_control.iter().filter(|(prefix, _)| request.path().starts_with(prefix.as_str()));
+    if let Some((_, value)) = prefixes.max_by_key(|(prefix, _)| prefix.len()) {
+      response.headers.set("Cache-Control", value);
+    }
+    response
+  }
+
+  /// Maps a request path onto a file system path under the document root.
+  ///
+  /// Returns `None` if the path is not valid percent-encoding or tries to climb out of the root
+  /// with `..`.
+  pub fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path)?;
+    let mut path = self.root.clone();
+
+    for segment in decoded.split('/') {
+      // check every segment on its own, so something like "a/../../etc" can't sneak through
+      let mut components = Path::new(segment).components();
+      match (components.next(), components.next()) {
+        (None, _) => continue, // empty segment from a double or trailing slash
+        (Some(Component::CurDir), None) => continue,
+        (Some(Component::Normal(name)), None) => path.push(name),
+        _ => return None, // "..", absolute paths and windows prefixes are all refused
+      }
+    }
+    Some(path)
+  }
+
+  fn not_found(&self) -> Response {
+    let body = fs::read(self.root.join(NOT_FOUND_FILE)).unwrap_or_else(|_| b"404 Not Found\n".to_vec());
+    Response::new(404)
+      .with_header("Content-Type", "text/html; charset=utf-8")
+      .with_body(body)
+  }
+}
+
+// the file's size and modification time, which change whenever its contents do (near enough)
+fn etag(metadata: &Metadata) -> String {
+  let modified = metadata.modified().ok()
+    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    .unwrap_or_default();
+  format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos())
+}
+
+// http dates only go down to the second, so drop the rest or If-Modified-Since never matches
+fn last_modified(metadata: &Metadata) -> Option<SystemTime> {
+  let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+  Some(UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()))
+}
+
+// If-None-Match wins over If-Modified-Since when a client sends both
+fn not_modified(request: &Request, etag: &str, last_modified: Option<SystemTime>) -> bool {
+  let mut tags = request.headers.get_all("If-None-Match").flat_map(|value| value.split(',')).peekable();
+  if tags.peek().is_some() {
+    return tags.map(str::trim).any(|tag| tag == "*" || weak_match(tag, etag));
+  }
+  match (request.header("If-Modified-Since").and_then(date::parse_http), last_modified) {
+    (Some(since), Some(modified)) => modified <= since,
+    _ => false,
+  }
+}
+
+// whether a Range request should be honoured: If-Range says "only if it's still this version"
+fn if_range_matches(request: &Request, etag: &str, last_modified: Option<SystemTime>) -> bool {
+  match request.header("If-Range").map(str::trim) {
+    None => true,
+    Some(tag) if tag.starts_with('"') => tag == etag, // ranges need a strong match
+    Some(tag) if tag.starts_with("W/") => false,
+    Some(modified) => date::parse_http(modified).is_some() && date::parse_http(modified) == last_modified,
+  }
+}
+
+// weak comparison ignores the W/ prefix, which is what conditional GETs use
+fn weak_match(a: &str, b: &str) -> bool {
+  a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// streams `file` back, or just the part of it the `range` header asks for
+fn ranged(range: Option<&str>, mut file: File, length: u64, content_type: &str) -> Response {
+  let response = Response::new(200)
+    .with_header("Content-Type", content_type)
+    .with_header("Accept-Ranges", "bytes");
+
+  match range::parse(range, length) {
+    ByteRange::Full => response.with_reader(file, Some(length)),
+    ByteRange::Partial(first, last) => {
+      if let Err(e) = file.seek(SeekFrom::Start(first)) {
+        log_event!(Level::Warn, "Failed to seek in a file: {}", e);
+        return Response::new(500).with_body("500 Internal Server Error\n");
+      }
+      let count = last - first + 1;
+      response.with_status(206)
+        .with_header("Content-Range", &format!("bytes {}-{}/{}", first, last, length))
+        .with_reader(file.take(count), Some(count))
+    }
+    ByteRange::Unsatisfiable => Response::new(416)
+      .with_header("Content-Range", &format!("bytes */{}", length))
+      .with_body("416 Range Not Satisfiable\n"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolves_paths_under_root() {
+    let files = StaticFiles::new("/srv/www");
+    assert_eq!(files.resolve("/"), Some(PathBuf::from("/srv/www")));
+    assert_eq!(files.resolve("/css/site.css"), Some(PathBuf::from("/srv/www/css/site.css")));
+    assert_eq!(files.resolve("/./a//b/"), Some(PathBuf::from("/srv/www/a/b")));
+    assert_eq!(files.resolve("/my%20file.txt"), Some(PathBuf::from("/srv/www/my file.txt")));
+  }
+
+  fn get(files: &StaticFiles, path: &str, headers: &str) -> Response {
+    let text = format!("GET {} HTTP/1.1\r\n{}\r\n", path, headers);
+    files.serve(&Request::read_from(&mut text.as_bytes()).unwrap())
+  }
+
+  #[test]
+  fn conditional_requests() {
+    let files = StaticFiles::new("public"); // tests run in the crate directory
+    let first = get(&files, "/index.html", "");
+    assert_eq!(first.status, 200);
+    let etag = first.headers.get("ETag").unwrap();
+    let modified = first.headers.get("Last-Modified").unwrap();
+
+    let cached = get(&files, "/index.html", &format!("If-None-Match: \"nope\", {}\r\n", etag));
+    assert_eq!(cached.status, 304);
+    assert!(cached.body.is_empty());
+    assert_eq!(cached.headers.get("ETag"), Some(etag));
+    assert_eq!(get(&files, "/index.html", &format!("If-None-Match: W/{}\r\n", etag)).status, 304);
+    assert_eq!(get(&files, "/index.html", &format!("If-Modified-Since: {}\r\n", modified)).status, 304);
+    assert_eq!(get(&files, "/index.html", "If-None-Match: \"stale\"\r\n").status, 200);
+    assert_eq!(get(&files, "/index.html", "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n").status, 200);
+
+    // a Range with a stale If-Range gets the whole file instead
+    let ranged = format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", etag);
+    assert_eq!(get(&files, "/index.html", &ranged).status, 206);
+    assert_eq!(get(&files, "/index.html", "Range: bytes=0-1\r\nIf-Range: \"stale\"\r\n").status, 200);
+  }
+
+  #[test]
+  fn cache_control_by_prefix() {
+    let files = StaticFiles::new("public")
+      .cache_control("/", "no-cache")
+      .cache_control("/index", "public, max-age=60");
+    assert_eq!(get(&files, "/index.html", "").headers.get("Cache-Control"), Some("public, max-age=60"));
+    assert_eq!(get(&files, "/", "").headers.get("Cache-Control"), Some("no-cache"));
+  }
+
+  #[test]
+  fn refuses_traversal() {
+    let files = StaticFiles::new("/srv/www");
+    assert_eq!(files.resolve("/../etc/passwd"), None);
+    assert_eq!(files.resolve("/a/../../etc/passwd"), None);
+    assert_eq!(files.resolve("/%2e%2e/etc/passwd"), None); // encoded dots are still dots
+    assert_eq!(files.resolve("/a%2f..%2f..%2fetc"), None); // and so are encoded slashes
+    assert_eq!(files.resolve("/file%00.html"), None);
+    assert_eq!(files.resolve("/bad%zzescape"), None);
+  }
+}
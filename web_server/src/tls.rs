@@ -0,0 +1,95 @@
+/*
+https listeners, built with the `tls` feature. the certificate chain and private key are read from
+pem files, the kind certbot or `openssl req -x509` produce. certs/ has a self-signed pair for
+localhost that's good enough for trying things out locally (browsers will complain about it).
+
+rustls does the handshake on the first read of a connection, so it happens on a worker thread
+under the same deadline as waiting for the first request
+*/
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::limits::DeadlineStream;
+use crate::transport::Transport;
+
+/// Settings shared by every https connection: the certificate and key, and the protocols on offer.
+pub type TlsConfig = Arc<ServerConfig>;
+
+/// Loads a certificate chain and the private key that goes with it, both pem encoded.
+pub fn load<P: AsRef<Path>, Q: AsRef<Path>>(cert_path: P, key_path: Q) -> io::Result<TlsConfig> {
+  let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+  let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+    .collect::<Result<Vec<_>, _>>()?;
+  if certs.is_empty() {
+    return Err(invalid(format!("no certificates in {}", cert_path.display())));
+  }
+  let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+    .ok_or_else(|| invalid(format!("no private key in {}", key_path.display())))?;
+
+  let provider = Arc::new(rustls::crypto::ring::default_provider());
+  let mut config = ServerConfig::builder_with_provider(provider)
+    .with_safe_default_protocol_versions()
+    .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+    .map_err(|e| invalid(format!("{} and {} don't make a usable pair: {}", cert_path.display(), key_path.display(), e)))?;
+  config.alpn_protocols = vec![b"http/1.1".to_vec()]; // all we speak
+  Ok(Arc::new(config))
+}
+
+fn invalid(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Starts a tls session on a freshly accepted connection. Nothing is sent until it's first read.
+pub fn accept(config: &TlsConfig, stream: TcpStream) -> io::Result<TlsStream> {
+  let connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+  // the deadline goes under the tls session rather than around it. rustls keeps reading the
+  // socket until it has a whole record, so a deadline checked once per tls read would let a
+  // client drip its handshake in forever
+  Ok(StreamOwned::new(connection, DeadlineStream::new(stream)))
+}
+
+pub type TlsStream = StreamOwned<ServerConnection, DeadlineStream>;
+
+impl Transport for TlsStream {
+  fn tcp(&self) -> &TcpStream {
+    self.sock.get_ref()
+  }
+
+  fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.sock.set_deadline(deadline)
+  }
+
+  fn close(&mut self) -> io::Result<()> {
+    self.conn.send_close_notify();
+    self.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn loads_the_example_pair() {
+    let config = load("certs/localhost.pem", "certs/localhost-key.pem").unwrap();
+    assert_eq!(config.alpn_protocols, [b"http/1.1".to_vec()]);
+  }
+
+  #[test]
+  fn bad_pairs() {
+    assert!(load("certs/missing.pem", "certs/localhost-key.pem").is_err());
+    // the certificate has no key in it, and the key no certificate
+    let error = load("certs/localhost.pem", "certs/localhost.pem").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(load("certs/localhost-key.pem", "certs/localhost-key.pem").is_err());
+  }
+}
@@ -0,0 +1,88 @@
+/*
+what a connection is carried over. plain http talks straight to the socket, https goes through a
+tls session wrapped around it (see tls.rs). the server reads and writes through this trait so the
+rest of the request handling doesn't care which one it has.
+
+once a response upgrades the connection (to a websocket, say) it's handed over as a Socket
+*/
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::time::Instant;
+
+use crate::limits::DeadlineStream;
+
+pub trait Transport: Read + Write + Send {
+  /// The socket underneath, for timeouts and addresses.
+  fn tcp(&self) -> &TcpStream;
+
+  /// Reads after `deadline` fail with `TimedOut`. `None` lets them wait for as long as it takes.
+  fn set_deadline(&mut self, deadline: Option<Instant>);
+
+  /// Ends the connection cleanly. For tls that means telling the client we're done, so it can
+  /// tell the end of a response apart from someone cutting the connection.
+  fn close(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Transport for DeadlineStream {
+  fn tcp(&self) -> &TcpStream {
+    self.get_ref()
+  }
+
+  fn set_deadline(&mut self, deadline: Option<Instant>) {
+    DeadlineStream::set_deadline(self, deadline)
+  }
+}
+
+/// A connection after an http response has switched it to another protocol: what the client
+/// already sent past its request is still in the buffer.
+pub trait Socket: BufRead + Write + Send {
+  /// Reads after `deadline` fail with `TimedOut`. `None` lets them wait for as long as it takes.
+  fn set_deadline(&mut self, deadline: Option<Instant>);
+}
+
+// the server's buffered connection, handed over on an upgrade. writes skip the buffer, which
+// only holds what's been read
+pub(crate) struct Upgraded<S: Transport>(pub(crate) BufReader<S>);
+
+impl<S: Transport> Read for Upgraded<S> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.0.read(buf)
+  }
+}
+
+impl<S: Transport> BufRead for Upgraded<S> {
+  fn fill_buf(&mut self) -> io::Result<&[u8]> {
+    self.0.fill_buf()
+  }
+
+  fn consume(&mut self, amount: usize) {
+    self.0.consume(amount)
+  }
+}
+
+impl<S: Transport> Write for Upgraded<S> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.get_mut().write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.get_mut().flush()
+  }
+}
+
+impl<S: Transport> Socket for Upgraded<S> {
+  fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.0.get_mut().set_deadline(deadline)
+  }
+}
+
+impl<S: Transport> Drop for Upgraded<S> {
+  fn drop(&mut self) {
+    let _ = self.0.get_mut().close();
+  }
+}
@@ -0,0 +1,709 @@
+/*
+websockets (rfc 6455): the http handshake that switches a connection over, the frame format, and a
+WebSocket that a handler sends and receives messages on.
+
+a websocket starts as a GET with "Upgrade: websocket". accept() checks it and answers 101, and the
+server then hands the connection to the handler on the pool worker it was already on. the handler
+keeps that worker for as long as the websocket is open, so size the pool (or max_workers) for the
+number of sockets you expect.
+
+  router.websocket("/echo", |_request, mut ws| {
+    while let Ok(message) = ws.recv() {
+      match message {
+        Message::Text(text) => { let _ = ws.send_text(&text); }
+        Message::Close(_) => break,
+        _ => {}
+      }
+    }
+  });
+
+to push updates while also listening, loop on recv_timeout() and send whatever has come in from
+elsewhere (a channel, say) in between
+*/
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::transport::Socket;
+
+// what the client's key is hashed with to prove we really speak websocket
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message, after putting fragments back together, a WebSocket accepts unless told otherwise.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+/// How long a client gets to finish a frame, or a fragmented message, once it has started one.
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how long close() waits for the client to answer with its own close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+  let mut sha1 = Sha1::new();
+  sha1.update(key.as_bytes());
+  sha1.update(GUID.as_bytes());
+  BASE64.encode(sha1.finalize())
+}
+
+/// Answers a websocket handshake with a 101, after which `handler` gets the connection as a
+/// WebSocket. Requests that aren't a proper handshake get a 400, or a 426 saying what to send.
+pub fn accept<F>(request: &Request, handler: F) -> Response
+  where
+    F: FnOnce(WebSocket) + Send + 'static,
+  {
+    let key = match handshake_key(request) {
+      Ok(key) => key,
+      Err(response) => return response,
+    };
+    Response::new(101)
+      .with_header("Upgrade", "websocket")
+      .with_header("Connection", "Upgrade")
+      .with_header("Sec-WebSocket-Accept", &accept_key(key))
+      .with_upgrade(move |socket| handler(WebSocket::new(socket)))
+  }
+
+// the client's key if this is a handshake we can accept, otherwise the response turning it down
+fn handshake_key(request: &Request) -> Result<&str, Response> {
+  let refuse = |status: u16, message: &str| {
+    Response::new(status)
+      .with_header("Content-Type", "text/plain; charset=utf-8")
+      .with_body(format!("{}\n", message))
+  };
+
+  if !request.headers.has_token("Upgrade", "websocket") {
+    return Err(refuse(426, "this is a websocket endpoint").with_header("Upgrade", "websocket"));
+  }
+  if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+    return Err(refuse(426, "only websocket version 13 is supported").with_header("Sec-WebSocket-Version", "13"));
+  }
+  if request.method != "GET" || request.version != "HTTP/1.1" || !request.headers.has_token("Connection", "upgrade") {
+    return Err(refuse(400, "a websocket handshake is an HTTP/1.1 GET with Connection: Upgrade"));
+  }
+  match request.header("Sec-WebSocket-Key").map(str::trim) {
+    Some(key) if BASE64.decode(key).map(|nonce| nonce.len() == 16).unwrap_or(false) => Ok(key),
+    _ => Err(refuse(400, "Sec-WebSocket-Key must be 16 base64 encoded bytes")),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+  Continuation, // the next piece of a fragmented message
+  Text,
+  Binary,
+  Close,
+  Ping,
+  Pong,
+}
+
+impl Opcode {
+  fn from_bits(bits: u8) -> Option<Opcode> {
+    match bits {
+      0x0 => Some(Opcode::Continuation),
+      0x1 => Some(Opcode::Text),
+      0x2 => Some(Opcode::Binary),
+      0x8 => Some(Opcode::Close),
+      0x9 => Some(Opcode::Ping),
+      0xA => Some(Opcode::Pong),
+      _ => None,
+    }
+  }
+
+  fn bits(self) -> u8 {
+    match self {
+      Opcode::Continuation => 0x0,
+      Opcode::Text => 0x1,
+      Opcode::Binary => 0x2,
+      Opcode::Close => 0x8,
+      Opcode::Ping => 0x9,
+      Opcode::Pong => 0xA,
+    }
+  }
+
+  /// Close, ping and pong: frames about the connection rather than part of a message.
+  pub fn is_control(self) -> bool {
+    matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+  }
+}
+
+/// One websocket frame. Messages are made of one or more of these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+  pub fin: bool, // the last frame of its message
+  pub opcode: Opcode,
+  pub mask: Option<[u8; 4]>, // clients must mask what they send, servers mustn't
+  pub payload: Vec<u8>,      // always unmasked
+}
+
+impl Frame {
+  /// A whole, unmasked message in one frame, which is how a server sends things.
+  pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+    Frame { fin: true, opcode, mask: None, payload }
+  }
+
+  /// Reads a frame, refusing payloads longer than `max_payload` bytes.
+  pub fn read_from<R: Read>(reader: &mut R, max_payload: usize) -> Result<Frame, FrameError> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    if head[0] & 0x70 != 0 {
+      return Err(FrameError::Protocol("reserved bits set without an extension that uses them"));
+    }
+    let opcode = Opcode::from_bits(head[0] & 0x0F).ok_or(FrameError::Protocol("unknown opcode"))?;
+
+    let length = match head[1] & 0x7F {
+      126 => {
+        let mut length = [0; 2];
+        reader.read_exact(&mut length)?;
+        u16::from_be_bytes(length) as u64
+      }
+      127 => {
+        let mut length = [0; 8];
+        reader.read_exact(&mut length)?;
+        u64::from_be_bytes(length)
+      }
+      length => length as u64,
+    };
+    if opcode.is_control() && (!fin || length > 125) {
+      return Err(FrameError::Protocol("control frames must be unfragmented and at most 125 bytes"));
+    }
+    if length > max_payload as u64 {
+      return Err(FrameError::TooLarge);
+    }
+
+    let mask = if head[1] & 0x80 != 0 {
+      let mut mask = [0; 4];
+      reader.read_exact(&mut mask)?;
+      Some(mask)
+    } else {
+      None
+    };
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    if let Some(mask) = mask {
+      apply_mask(&mut payload, mask);
+    }
+    Ok(Frame { fin, opcode, mask, payload })
+  }
+
+  /// Writes the frame, masking the payload on the way out if it has a mask.
+  pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(14 + self.payload.len()); // all in one write, so it goes out as one packet
+    bytes.push(if self.fin { 0x80 } else { 0 } | self.opcode.bits());
+    let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+    match self.payload.len() {
+      length if length < 126 => bytes.push(mask_bit | length as u8),
+      length if length <= u16::MAX as usize => {
+        bytes.push(mask_bit | 126);
+        bytes.extend_from_slice(&(length as u16).to_be_bytes());
+      }
+      length => {
+        bytes.push(mask_bit | 127);
+        bytes.extend_from_slice(&(length as u64).to_be_bytes());
+      }
+    }
+    let start = bytes.len() + if self.mask.is_some() { 4 } else { 0 };
+    if let Some(mask) = self.mask {
+      bytes.extend_from_slice(&mask);
+    }
+    bytes.extend_from_slice(&self.payload);
+    if let Some(mask) = self.mask {
+      apply_mask(&mut bytes[start..], mask);
+    }
+    writer.write_all(&bytes)?;
+    writer.flush()
+  }
+}
+
+// masking and unmasking are the same xor
+fn apply_mask(bytes: &mut [u8], mask: [u8; 4]) {
+  for (i, byte) in bytes.iter_mut().enumerate() {
+    *byte ^= mask[i % 4];
+  }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+  Io(io::Error),
+  Protocol(&'static str), // the client broke the rules
+  TooLarge,               // a frame or message bigger than we're willing to hold
+  BadUtf8,                // a text message that isn't utf-8
+}
+
+impl FrameError {
+  /// The status code sent in the close frame when a connection is dropped over this error.
+  pub fn close_code(&self) -> u16 {
+    match self {
+      FrameError::Io(_) => 1011,
+      FrameError::Protocol(_) => 1002,
+      FrameError::TooLarge => 1009,
+      FrameError::BadUtf8 => 1007,
+    }
+  }
+}
+
+impl fmt::Display for FrameError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FrameError::Io(e) => write!(f, "{}", e),
+      FrameError::Protocol(message) => write!(f, "websocket protocol error: {}", message),
+      FrameError::TooLarge => write!(f, "websocket message is too large"),
+      FrameError::BadUtf8 => write!(f, "websocket text message is not valid utf-8"),
+    }
+  }
+}
+
+impl Error for FrameError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      FrameError::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for FrameError {
+  fn from(error: io::Error) -> FrameError {
+    FrameError::Io(error)
+  }
+}
+
+/// What comes in over a websocket, and what can be sent out on one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+  Text(String),
+  Binary(Vec<u8>),
+  Ping(Vec<u8>), // already answered with a pong by the time recv returns it
+  Pong(Vec<u8>),
+  Close(Option<(u16, String)>), // the status code and reason, if the client gave one
+}
+
+/// An open websocket connection.
+pub struct WebSocket {
+  socket: Box<dyn Socket>,
+  max_message_bytes: usize,
+  frame_timeout: Duration,
+  fragments: Option<(Opcode, Vec<u8>)>, // a message that's arriving in pieces
+  close_sent: bool,
+  closed: bool, // nothing more is coming in
+}
+
+impl WebSocket {
+  /// Speaks websocket on a connection that has already been through the handshake.
+  pub fn new(socket: Box<dyn Socket>) -> WebSocket {
+    WebSocket {
+      socket,
+      max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+      frame_timeout: DEFAULT_FRAME_TIMEOUT,
+      fragments: None,
+      close_sent: false,
+      closed: false,
+    }
+  }
+
+  /// Closes the connection with 1009 Message Too Big if a message is longer than `bytes`.
+  pub fn max_message_bytes(mut self, bytes: usize) -> WebSocket {
+    self.max_message_bytes = bytes;
+    self
+  }
+
+  /// How long the client gets to finish a frame or fragmented message once it has started one.
+  pub fn frame_timeout(mut self, timeout: Duration) -> WebSocket {
+    self.frame_timeout = timeout;
+    self
+  }
+
+  /// Waits for the next message, however long that takes. Pings are answered before they're
+  /// returned. After a `Close` the connection is done and further calls fail.
+  pub fn recv(&mut self) -> io::Result<Message> {
+    self.read_message(None).map(|message| message.expect("nothing times out without a deadline"))
+  }
+
+  /// Like `recv`, but gives up and returns `None` if no message has started to arrive within
+  /// `wait`.
+  pub fn recv_timeout(&mut self, wait: Duration) -> io::Result<Option<Message>> {
+    self.read_message(Some(Instant::now() + wait))
+  }
+
+  pub fn send(&mut self, message: Message) -> io::Result<()> {
+    match message {
+      Message::Text(text) => self.write_frame(Frame::new(Opcode::Text, text.into_bytes())),
+      Message::Binary(bytes) => self.write_frame(Frame::new(Opcode::Binary, bytes)),
+      Message::Ping(payload) => self.write_frame(Frame::new(Opcode::Ping, payload)),
+      Message::Pong(payload) => self.write_frame(Frame::new(Opcode::Pong, payload)),
+      Message::Close(Some((code, reason))) => self.close(code, &reason),
+      Message::Close(None) => self.close(1000, ""),
+    }
+  }
+
+  pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+    self.write_frame(Frame::new(Opcode::Text, text.as_bytes().to_vec()))
+  }
+
+  pub fn send_binary(&mut self, bytes: &[u8]) -> io::Result<()> {
+    self.write_frame(Frame::new(Opcode::Binary, bytes.to_vec()))
+  }
+
+  /// Starts the closing handshake and waits briefly for the client to finish it. Anything else
+  /// the client sends in the meantime is dropped.
+  pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+    if !self.close_sent {
+      self.send_close(code, reason)?;
+    }
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while !self.closed {
+      match self.read_message(Some(deadline)) {
+        Ok(Some(_)) => {}
+        Ok(None) | Err(_) => break, // it's had its chance
+      }
+    }
+    self.closed = true;
+    Ok(())
+  }
+
+  fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+    // a code that isn't allowed on the wire, say 1005 handed back from a Close we received,
+    // goes out as a plain normal closure
+    let code = if valid_close_code(code) { code } else { 1000 };
+    let mut payload = code.to_be_bytes().to_vec();
+    // control frames are small, so a long reason gets cut short, but not in the middle of a
+    // character or the client would rightly refuse the frame as bad utf-8
+    let mut end = reason.len().min(125 - payload.len());
+    while !reason.is_char_boundary(end) {
+      end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    self.write_frame(Frame::new(Opcode::Close, payload))?;
+    self.close_sent = true;
+    Ok(())
+  }
+
+  fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+    if self.close_sent {
+      return Err(io::Error::new(io::ErrorKind::NotConnected, "the websocket is closed"));
+    }
+    frame.write_to(&mut self.socket)
+  }
+
+  // the next message, or None if `wait_until` passes before one starts to arrive
+  fn read_message(&mut self, wait_until: Option<Instant>) -> io::Result<Option<Message>> {
+    if self.closed {
+      return Err(io::Error::new(io::ErrorKind::NotConnected, "the websocket is closed"));
+    }
+    loop {
+      // wait for the next frame as long as the caller likes, unless we're partway through a
+      // fragmented message
+      let deadline = match self.fragments {
+        Some(_) => Some(Instant::now() + self.frame_timeout),
+        None => wait_until,
+      };
+      self.socket.set_deadline(deadline);
+      match self.socket.fill_buf() {
+        Ok([]) => { // gone without saying goodbye
+          self.closed = true;
+          return Ok(Some(Message::Close(None)));
+        }
+        Ok(_) => {}
+        Err(ref e) if is_timeout(e) && self.fragments.is_none() => return Ok(None),
+        Err(e) => return Err(self.fail(FrameError::Io(e))),
+      }
+
+      self.socket.set_deadline(Some(Instant::now() + self.frame_timeout));
+      let frame = match Frame::read_from(&mut self.socket, self.max_message_bytes) {
+        Ok(frame) => frame,
+        Err(e) => return Err(self.fail(e)),
+      };
+      if frame.mask.is_none() {
+        return Err(self.fail(FrameError::Protocol("client frames must be masked")));
+      }
+
+      match frame.opcode {
+        Opcode::Ping => {
+          self.write_frame(Frame::new(Opcode::Pong, frame.payload.clone()))?;
+          return Ok(Some(Message::Ping(frame.payload)));
+        }
+        Opcode::Pong => return Ok(Some(Message::Pong(frame.payload))),
+        Opcode::Close => {
+          let reason = match parse_close(&frame.payload) {
+            Ok(reason) => reason,
+            Err(e) => return Err(self.fail(e)),
+          };
+          if !self.close_sent { // answer with the same code to finish the handshake
+            let code = reason.as_ref().map(|(code, _)| *code).unwrap_or(1000);
+            self.send_close(code, "")?;
+          }
+          self.closed = true;
+          return Ok(Some(Message::Close(reason)));
+        }
+        Opcode::Text | Opcode::Binary if self.fragments.is_some() => {
+          return Err(self.fail(FrameError::Protocol("new message before the last one was finished")));
+        }
+        Opcode::Text | Opcode::Binary if frame.fin => {
+          return self.message(frame.opcode, frame.payload).map(Some);
+        }
+        Opcode::Text | Opcode::Binary => self.fragments = Some((frame.opcode, frame.payload)),
+        Opcode::Continuation => {
+          let (opcode, mut payload) = match self.fragments.take() {
+            Some(fragments) => fragments,
+            None => return Err(self.fail(FrameError::Protocol("continuation frame without a message to continue"))),
+          };
+          if payload.len() + frame.payload.len() > self.max_message_bytes {
+            return Err(self.fail(FrameError::TooLarge));
+          }
+          payload.extend_from_slice(&frame.payload);
+          if frame.fin {
+            return self.message(opcode, payload).map(Some);
+          }
+          self.fragments = Some((opcode, payload));
+        }
+      }
+    }
+  }
+
+  fn message(&mut self, opcode: Opcode, payload: Vec<u8>) -> io::Result<Message> {
+    match opcode {
+      Opcode::Text => match String::from_utf8(payload) {
+        Ok(text) => Ok(Message::Text(text)),
+        Err(_) => Err(self.fail(FrameError::BadUtf8)),
+      },
+      _ => Ok(Message::Binary(payload)),
+    }
+  }
+
+  // gives up on the connection, telling the client why if it's their fault, and turns the error
+  // into one for the handler
+  fn fail(&mut self, error: FrameError) -> io::Error {
+    self.closed = true;
+    match error {
+      FrameError::Io(e) => e,
+      error => {
+        if !self.close_sent {
+          let _ = self.send_close(error.close_code(), "");
+        }
+        io::Error::new(io::ErrorKind::InvalidData, error)
+      }
+    }
+  }
+}
+
+impl Drop for WebSocket {
+  // a handler that returns without closing still says goodbye
+  fn drop(&mut self) {
+    if !self.close_sent && !self.closed {
+      let _ = self.send_close(1000, "");
+    }
+  }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<(u16, String)>, FrameError> {
+  match payload.len() {
+    0 => Ok(None),
+    1 => Err(FrameError::Protocol("close frame with half a status code")),
+    _ => {
+      let code = u16::from_be_bytes([payload[0], payload[1]]);
+      if !valid_close_code(code) {
+        return Err(FrameError::Protocol("close frame with a status code that can't be sent"));
+      }
+      let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| FrameError::BadUtf8)?;
+      Ok(Some((code, reason)))
+    }
+  }
+}
+
+// the status codes allowed in a close frame (rfc 6455 section 7.4 and the iana registry after it).
+// 1005, 1006 and 1015 only exist for apis to report a missing code or a dropped connection, and
+// the rest below 3000 are reserved
+fn valid_close_code(code: u16) -> bool {
+  matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// read timeouts show up as WouldBlock on unix and TimedOut on windows
+fn is_timeout(error: &io::Error) -> bool {
+  error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use std::sync::{Arc, Mutex};
+
+  // a connection that reads from a script of bytes and records what's written to it
+  struct Pipe {
+    input: Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+  }
+
+  impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.input.read(buf)
+    }
+  }
+
+  impl BufRead for Pipe {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+      self.input.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+      self.input.consume(amount)
+    }
+  }
+
+  impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Socket for Pipe {
+    fn set_deadline(&mut self, _deadline: Option<Instant>) {}
+  }
+
+  // frames the way a client sends them
+  fn client_frame(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    Frame { fin, opcode, mask: Some([0x12, 0x34, 0x56, 0x78]), payload: payload.to_vec() }
+      .write_to(&mut bytes).unwrap();
+    bytes
+  }
+
+  fn websocket(input: Vec<u8>) -> (WebSocket, Arc<Mutex<Vec<u8>>>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let pipe = Pipe { input: Cursor::new(input), output: Arc::clone(&output) };
+    (WebSocket::new(Box::new(pipe)), output)
+  }
+
+  // the frames the server wrote
+  fn sent(output: &Arc<Mutex<Vec<u8>>>) -> Vec<Frame> {
+    let bytes = output.lock().unwrap().clone();
+    let mut reader = &bytes[..];
+    let mut frames = Vec::new();
+    while !reader.is_empty() {
+      frames.push(Frame::read_from(&mut reader, usize::MAX).unwrap());
+    }
+    frames
+  }
+
+  fn handshake(extra: &str) -> Request {
+    let text = format!("GET /ws HTTP/1.1\r\nHost: x\r\n{}\r\n", extra);
+    Request::read_from(&mut text.as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn accept_key_from_the_rfc() {
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+  }
+
+  #[test]
+  fn handshakes() {
+    let good = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n";
+    let response = accept(&handshake(&format!("{}Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", good)), |_| {});
+    assert_eq!(response.status, 101);
+    assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert!(response.upgrade.is_some());
+
+    let plain = accept(&handshake(""), |_| {});
+    assert_eq!(plain.status, 426);
+    assert!(plain.upgrade.is_none());
+    let old = accept(&handshake("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\n"), |_| {});
+    assert_eq!((old.status, old.headers.get("Sec-WebSocket-Version")), (426, Some("13")));
+    let short_key = accept(&handshake(&format!("{}Sec-WebSocket-Key: c2hvcnQ=\r\n", good)), |_| {});
+    assert_eq!(short_key.status, 400);
+  }
+
+  #[test]
+  fn frame_lengths() {
+    for &length in &[0, 125, 126, 65_535, 65_536] {
+      let frame = Frame::new(Opcode::Binary, vec![7; length]);
+      let mut bytes = Vec::new();
+      frame.write_to(&mut bytes).unwrap();
+      assert_eq!(Frame::read_from(&mut &bytes[..], usize::MAX).unwrap(), frame);
+    }
+    let masked = client_frame(true, Opcode::Text, b"hello");
+    assert_ne!(&masked[6..], b"hello");
+    assert_eq!(Frame::read_from(&mut &masked[..], 100).unwrap().payload, b"hello");
+    assert!(matches!(Frame::read_from(&mut &masked[..], 4), Err(FrameError::TooLarge)));
+  }
+
+  #[test]
+  fn messages_pings_and_fragments() {
+    let mut input = client_frame(true, Opcode::Text, b"hi");
+    input.extend(client_frame(false, Opcode::Binary, b"ab"));
+    input.extend(client_frame(true, Opcode::Ping, b"?")); // allowed in the middle of a message
+    input.extend(client_frame(false, Opcode::Continuation, b"cd"));
+    input.extend(client_frame(true, Opcode::Continuation, b"ef"));
+    input.extend(client_frame(true, Opcode::Close, b"\x03\xe8bye"));
+    let (mut ws, output) = websocket(input);
+
+    assert_eq!(ws.recv().unwrap(), Message::Text(String::from("hi")));
+    assert_eq!(ws.recv().unwrap(), Message::Ping(b"?".to_vec()));
+    assert_eq!(ws.recv().unwrap(), Message::Binary(b"abcdef".to_vec()));
+    ws.send_text("sent").unwrap();
+    assert_eq!(ws.recv().unwrap(), Message::Close(Some((1000, String::from("bye")))));
+    assert!(ws.recv().is_err());
+    assert!(ws.send_text("too late").is_err());
+
+    let frames = sent(&output);
+    assert_eq!(frames[0], Frame::new(Opcode::Pong, b"?".to_vec()));
+    assert_eq!(frames[1], Frame::new(Opcode::Text, b"sent".to_vec()));
+    assert_eq!(frames[2], Frame::new(Opcode::Close, b"\x03\xe8".to_vec())); // the close echoed back
+    assert_eq!(frames.len(), 3);
+  }
+
+  #[test]
+  fn protocol_errors_close_the_connection() {
+    let mut unmasked = Vec::new();
+    Frame::new(Opcode::Text, b"hi".to_vec()).write_to(&mut unmasked).unwrap();
+    let (mut ws, output) = websocket(unmasked);
+    assert_eq!(ws.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(sent(&output), [Frame::new(Opcode::Close, vec![0x03, 0xEA])]); // 1002
+
+    let (mut ws, output) = websocket(client_frame(true, Opcode::Text, b"\xff\xfe"));
+    assert!(ws.recv().is_err());
+    assert_eq!(sent(&output)[0].payload, [0x03, 0xEF]); // 1007
+
+    let (mut ws, _) = websocket(client_frame(true, Opcode::Continuation, b"?"));
+    assert!(ws.recv().is_err());
+  }
+
+  #[test]
+  fn close_codes() {
+    let (mut ws, output) = websocket(client_frame(true, Opcode::Close, b"\x0f\xa0")); // 4000, an app's own
+    assert_eq!(ws.recv().unwrap(), Message::Close(Some((4000, String::new()))));
+    assert_eq!(sent(&output), [Frame::new(Opcode::Close, vec![0x0F, 0xA0])]);
+
+    for code in [999u16, 1004, 1005, 1006, 1015, 2999, 5000] {
+      let (mut ws, output) = websocket(client_frame(true, Opcode::Close, &code.to_be_bytes()));
+      assert!(ws.recv().is_err(), "{} was accepted", code);
+      assert_eq!(sent(&output), [Frame::new(Opcode::Close, vec![0x03, 0xEA])]); // 1002
+    }
+
+    // a code that can't be sent goes out as 1000, and a long reason is cut between characters
+    let (mut ws, output) = websocket(Vec::new());
+    ws.close(1006, &"é".repeat(100)).unwrap();
+    let payload = &sent(&output)[0].payload;
+    assert_eq!(payload.len(), 124);
+    assert_eq!(parse_close(payload).unwrap(), Some((1000, "é".repeat(61))));
+  }
+
+  #[test]
+  fn goodbye_on_drop() {
+    let (ws, output) = websocket(Vec::new());
+    drop(ws);
+    assert_eq!(sent(&output), [Frame::new(Opcode::Close, vec![0x03, 0xE8])]);
+  }
+}
@@ -0,0 +1,225 @@
+/*
+shared by the integration tests: a Server running on a port the os picked, and a bare bones http
+client that reads back exactly what came over the wire, so tests can see the status line and
+headers the server really sent rather than what a friendlier client would make of them
+*/
+
+#![allow(dead_code)] // each test file uses a different part of this
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use web_server::{Headers, Router, Server, ShutdownHandle};
+
+// long enough for a slow ci box, short enough that a test waiting on a response that never comes
+// fails instead of hanging
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Server running on its own thread. It's shut down when this is dropped.
+pub struct TestServer {
+  addrs: Vec<SocketAddr>, // every listener's, in the order they were bound
+  shutdown: ShutdownHandle,
+  thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl TestServer {
+  pub fn start(router: Router) -> TestServer {
+    TestServer::start_with(router, |server| server)
+  }
+
+  /// Starts a server on 127.0.0.1 with an ephemeral port, letting `configure` set it up first.
+  pub fn start_with<F: FnOnce(Server) -> Server>(router: Router, configure: F) -> TestServer {
+    let server = configure(Server::bind("127.0.0.1:0", router).expect("couldn't bind a test server"));
+    let addrs = server.local_addrs().unwrap();
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run());
+    TestServer { addrs, shutdown, thread: Some(thread) }
+  }
+
+  /// The address of the listener the server was started with.
+  pub fn addr(&self) -> SocketAddr {
+    self.addrs[0]
+  }
+
+  /// Every listener's address, the one the server was started with first.
+  pub fn addrs(&self) -> &[SocketAddr] {
+    &self.addrs
+  }
+
+  pub fn client(&self) -> Client {
+    Client::connect(self.addr())
+  }
+
+  /// GETs `path` on a connection of its own.
+  pub fn get(&self, path: &str) -> TestResponse {
+    self.client().get(path)
+  }
+
+  /// Asks the server to stop without waiting for it.
+  pub fn shutdown(&self) {
+    self.shutdown.shutdown();
+  }
+
+  /// Stops the server and waits for `run` to return, handing back what it returned.
+  pub fn stop(mut self) -> io::Result<()> {
+    self.shutdown.shutdown();
+    self.thread.take().unwrap().join().expect("server thread panicked")
+  }
+}
+
+impl Drop for TestServer {
+  fn drop(&mut self) {
+    self.shutdown.shutdown();
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+/// One connection to a server, kept open across requests.
+pub struct Client {
+  reader: BufReader<TcpStream>,
+}
+
+impl Client {
+  pub fn connect(addr: SocketAddr) -> Client {
+    let stream = TcpStream::connect(addr).expect("couldn't connect to the test server");
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    Client { reader: BufReader::new(stream) }
+  }
+
+  /// The local address of this connection, which tells connections apart.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.reader.get_ref().local_addr().unwrap()
+  }
+
+  /// Writes `raw` as is, for requests a well behaved client wouldn't send.
+  pub fn send(&mut self, raw: &[u8]) {
+    self.reader.get_mut().write_all(raw).unwrap();
+  }
+
+  /// Sends an HTTP/1.1 request and reads the response to it.
+  pub fn request(&mut self, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> TestResponse {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+    for (name, value) in headers {
+      head += &format!("{}: {}\r\n", name, value);
+    }
+    if !body.is_empty() {
+      head += &format!("Content-Length: {}\r\n", body.len());
+    }
+    head += "\r\n";
+    self.send(head.as_bytes());
+    self.send(body);
+    if method == "HEAD" {
+      self.read_response_head()
+    } else {
+      self.read_response()
+    }
+  }
+
+  pub fn get(&mut self, path: &str) -> TestResponse {
+    self.request("GET", path, &[], b"")
+  }
+
+  /// Reads a response, body and all.
+  pub fn read_response(&mut self) -> TestResponse {
+    let mut response = self.read_response_head();
+    if response.status == 204 || response.status == 304 {
+      return response;
+    }
+    response.body = if response.headers.has_token("Transfer-Encoding", "chunked") {
+      self.read_chunked()
+    } else if let Some(length) = response.header("Content-Length") {
+      let mut body = vec![0; length.parse().expect("bad Content-Length")];
+      self.reader.read_exact(&mut body).unwrap();
+      body
+    } else {
+      let mut body = Vec::new(); // the body runs until the server closes the connection
+      self.reader.read_to_end(&mut body).unwrap();
+      body
+    };
+    response
+  }
+
+  /// Reads the status line and headers of a response, leaving any body unread. What a HEAD
+  /// request gets back.
+  pub fn read_response_head(&mut self) -> TestResponse {
+    let status_line = self.read_line();
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    assert!(version.starts_with("HTTP/1."), "not a status line: {:?}", status_line);
+    let status = parts.next().and_then(|code| code.parse().ok())
+      .unwrap_or_else(|| panic!("not a status line: {:?}", status_line));
+
+    let mut headers = Headers::new();
+    loop {
+      let line = self.read_line();
+      if line.is_empty() {
+        break;
+      }
+      let (name, value) = line.split_once(':').unwrap_or_else(|| panic!("bad header: {:?}", line));
+      headers.append(name.trim(), value.trim());
+    }
+    TestResponse { status, headers, body: Vec::new() }
+  }
+
+  /// Whether the server has closed its end. Anything still unread counts as not closed, and on an
+  /// open connection this only returns once the read times out, so it's for checking closes.
+  pub fn is_closed(&mut self) -> bool {
+    match self.reader.fill_buf() {
+      Ok(buf) => buf.is_empty(),
+      Err(e) => e.kind() == io::ErrorKind::ConnectionReset,
+    }
+  }
+
+  /// Stops sending, the way a client that's done with the connection would.
+  pub fn shutdown_write(&mut self) {
+    self.reader.get_ref().shutdown(Shutdown::Write).unwrap();
+  }
+
+  fn read_line(&mut self) -> String {
+    let mut line = String::new();
+    let read = self.reader.read_line(&mut line).expect("failed to read a response line");
+    assert!(read > 0, "the server closed the connection instead of answering");
+    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+  }
+
+  fn read_chunked(&mut self) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+      let size_line = self.read_line();
+      let size = usize::from_str_radix(size_line.split(';').next().unwrap().trim(), 16)
+        .unwrap_or_else(|_| panic!("bad chunk size: {:?}", size_line));
+      if size == 0 {
+        while !self.read_line().is_empty() {} // trailers, if any
+        return body;
+      }
+      let start = body.len();
+      body.resize(start + size, 0);
+      self.reader.read_exact(&mut body[start..]).unwrap();
+      self.read_line(); // the CRLF after the chunk
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct TestResponse {
+  pub status: u16,
+  pub headers: Headers,
+  pub body: Vec<u8>,
+}
+
+impl TestResponse {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.get(name)
+  }
+
+  /// The body as utf-8.
+  pub fn text(&self) -> &str {
+    std::str::from_utf8(&self.body).expect("response body isn't utf-8")
+  }
+}
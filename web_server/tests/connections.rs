@@ -0,0 +1,207 @@
+/*
+what happens to the connection itself: reusing it for more requests, closing it when asked to,
+and answering requests the server can't make sense of
+*/
+
+mod common;
+
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "tls")]
+use std::{io, io::prelude::*, net::TcpStream, time::Instant};
+
+use common::{Client, TestServer};
+use web_server::server::ConnectionConfig;
+use web_server::{OverflowPolicy, Response, Router};
+
+// answers with the client's address, so a test can tell whether two requests shared a connection
+fn router() -> Router {
+  let mut router = Router::new();
+  router.get("/peer", |req| Response::text(req.peer.unwrap().to_string()));
+  router.post("/echo", |req| Response::text(req.body.clone()));
+  router
+}
+
+#[test]
+fn keep_alive() {
+  let server = TestServer::start(router());
+  let mut client = server.client();
+
+  let first = client.get("/peer");
+  assert_eq!(first.header("Connection"), Some("keep-alive"));
+  assert_eq!(first.text(), client.local_addr().to_string());
+  let second = client.get("/peer");
+  assert_eq!(second.text(), first.text()); // same connection both times
+}
+
+#[test]
+fn pipelining() {
+  let server = TestServer::start(router());
+  let mut client = server.client();
+  client.send(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\none\
+                POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\ntwo\
+                GET /missing HTTP/1.1\r\n\r\n");
+  assert_eq!(client.read_response().text(), "one");
+  assert_eq!(client.read_response().text(), "two");
+  assert_eq!(client.read_response().status, 404);
+}
+
+#[test]
+fn closing_connections() {
+  let server = TestServer::start(router());
+
+  let mut client = server.client();
+  let response = client.request("GET", "/peer", &[("Connection", "close")], b"");
+  assert_eq!(response.header("Connection"), Some("close"));
+  assert!(client.is_closed());
+
+  // HTTP/1.0 closes unless asked not to
+  let mut client = server.client();
+  client.send(b"GET /peer HTTP/1.0\r\n\r\n");
+  assert_eq!(client.read_response().header("Connection"), Some("close"));
+  assert!(client.is_closed());
+
+  let mut client = server.client();
+  client.send(b"GET /peer HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+  assert_eq!(client.read_response().header("Connection"), Some("keep-alive"));
+  assert_eq!(client.get("/peer").status, 200);
+}
+
+#[test]
+fn connection_limits() {
+  let config = ConnectionConfig {
+    keep_alive_timeout: Duration::from_millis(200),
+    max_requests: 2,
+    ..ConnectionConfig::default()
+  };
+  let server = TestServer::start_with(router(), |server| server.connection_config(config));
+
+  let mut client = server.client();
+  assert_eq!(client.get("/peer").header("Connection"), Some("keep-alive"));
+  assert_eq!(client.get("/peer").header("Connection"), Some("close")); // that was its last one
+  assert!(client.is_closed());
+
+  let mut idle = server.client();
+  assert_eq!(idle.get("/peer").status, 200);
+  thread::sleep(Duration::from_millis(400));
+  assert!(idle.is_closed());
+}
+
+#[test]
+fn malformed_requests() {
+  let server = TestServer::start(router());
+  let answer = |raw: &[u8]| {
+    let mut client = server.client();
+    client.send(raw);
+    let response = client.read_response();
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(client.is_closed(), "still open after {:?}", String::from_utf8_lossy(raw));
+    response.status
+  };
+
+  assert_eq!(answer(b"NONSENSE\r\n\r\n"), 400);
+  assert_eq!(answer(b"GET /peer HTTP/1.1\r\nno colon here\r\n\r\n"), 400);
+  assert_eq!(answer(b"POST /echo HTTP/1.1\r\nContent-Length: lots\r\n\r\n"), 400);
+  assert_eq!(answer(b"GET /peer HTTP/2.0\r\n\r\n"), 505);
+  assert_eq!(answer(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), 501);
+  let huge = format!("GET /peer HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(16 * 1024));
+  assert_eq!(answer(huge.as_bytes()), 431);
+}
+
+#[test]
+fn request_limits() {
+  let config = ConnectionConfig {
+    header_timeout: Duration::from_millis(200),
+    max_body_bytes: 16,
+    ..ConnectionConfig::default()
+  };
+  let server = TestServer::start_with(router(), |server| server.connection_config(config));
+
+  let mut client = server.client();
+  let too_big = client.request("POST", "/echo", &[], &[b'a'; 17]);
+  assert_eq!(too_big.status, 413);
+
+  let mut slow = server.client();
+  slow.send(b"GET /peer HTTP/1.1\r\n"); // and then nothing
+  assert_eq!(slow.read_response().status, 408);
+  assert!(slow.is_closed());
+}
+
+#[test]
+fn dropped_connections_hear_why() {
+  // /stuck holds the only worker until it's released
+  let (started, has_started) = mpsc::channel();
+  let (release, wait) = mpsc::channel::<()>();
+  let (started, wait) = (Mutex::new(started), Mutex::new(wait));
+  let mut router = router();
+  router.get("/stuck", move |_req| {
+    started.lock().unwrap().send(()).unwrap();
+    let _ = wait.lock().unwrap().recv();
+    Response::text("done")
+  });
+  let server = TestServer::start_with(router, |server| {
+    server.workers(1).queue_capacity(1, OverflowPolicy::DropOldest)
+  });
+
+  let mut stuck = server.client();
+  stuck.send(b"GET /stuck HTTP/1.1\r\nConnection: close\r\n\r\n"); // so the worker moves on after
+  has_started.recv_timeout(Duration::from_secs(5)).unwrap();
+  let mut oldest = server.client(); // waits in the queue
+  let mut newest = server.client(); // and pushes the one above out
+
+  let response = oldest.read_response();
+  assert_eq!((response.status, response.header("Connection")), (503, Some("close")));
+  assert!(oldest.is_closed());
+  drop(release);
+  assert_eq!(stuck.read_response().text(), "done");
+  assert_eq!(newest.get("/peer").status, 200);
+}
+
+#[test]
+fn clients_hanging_up() {
+  let server = TestServer::start(router());
+  let mut client = server.client();
+  client.send(b"POST /echo HTTP/1.1\r\nContent-Length: 10\r\n\r\nhalf");
+  client.shutdown_write();
+  assert_eq!(client.read_response().status, 400);
+
+  let mut quiet = server.client();
+  quiet.shutdown_write(); // connected and left without a word, nothing to answer
+  assert!(quiet.is_closed());
+
+  assert_eq!(Client::connect(server.addr()).get("/peer").status, 200); // and the server carries on
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn slow_tls_handshakes() {
+  let config = ConnectionConfig { keep_alive_timeout: Duration::from_millis(300), ..ConnectionConfig::default() };
+  let tls = web_server::tls::load("certs/localhost.pem", "certs/localhost-key.pem").unwrap();
+  let server = TestServer::start_with(router(), |server| {
+    server.connection_config(config).also_bind_tls("127.0.0.1:0", tls).unwrap()
+  });
+
+  // the start of a handshake record that says 512 bytes are coming, then those bytes one at a
+  // time, each well within the timeout. rustls waits for the whole record in a single read
+  let mut stream = TcpStream::connect(server.addrs()[1]).unwrap();
+  stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+  stream.write_all(&[0x16, 0x03, 0x01, 0x02, 0x00]).unwrap();
+  let started = Instant::now();
+  let closed = loop {
+    if started.elapsed() > Duration::from_secs(5) {
+      break false;
+    }
+    if stream.write_all(&[0]).is_err() {
+      break true;
+    }
+    match stream.read(&mut [0; 64]) {
+      Ok(0) => break true,
+      Ok(_) => {} // an alert, say
+      Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+      Err(_) => break true,
+    }
+  };
+  assert!(closed, "a dripped handshake kept the connection open");
+  assert!(started.elapsed() < Duration::from_secs(2));
+}
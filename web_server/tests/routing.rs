@@ -0,0 +1,94 @@
+/*
+requests going through a real server to the right handler, and the answers when there isn't one
+*/
+
+mod common;
+
+use common::TestServer;
+use web_server::middleware::RequestId;
+use web_server::{Response, Router, StaticFiles};
+
+fn router() -> Router {
+  let mut router = Router::new();
+  router.get("/", |_req| Response::text("home"));
+  router.get("/users/:id", |req| Response::text(format!("user {}", req.param("id").unwrap())));
+  router.post("/users", |req| Response::text(format!("created {}", String::from_utf8_lossy(&req.body))).with_status(201));
+  router.delete("/users/:id", |req| Response::text(format!("deleted {}", req.param("id").unwrap())));
+  router.get("/files/*path", |req| Response::text(req.param("path").unwrap().to_string()));
+  router
+}
+
+#[test]
+fn routes_by_method_and_path() {
+  let server = TestServer::start(router());
+  let mut client = server.client();
+
+  let home = client.get("/");
+  assert_eq!((home.status, home.text()), (200, "home"));
+  assert_eq!(home.header("Content-Type"), Some("text/plain; charset=utf-8"));
+  assert_eq!(client.get("/users/42").text(), "user 42");
+  assert_eq!(client.get("/files/css/site.css").text(), "css/site.css");
+
+  let created = client.request("POST", "/users", &[], b"ada");
+  assert_eq!((created.status, created.text()), (201, "created ada"));
+  let deleted = client.request("DELETE", "/users/7", &[], b"");
+  assert_eq!((deleted.status, deleted.text()), (200, "deleted 7"));
+}
+
+#[test]
+fn query_strings_and_escapes() {
+  let server = TestServer::start(router());
+  assert_eq!(server.get("/users/42?verbose=true").text(), "user 42");
+  assert_eq!(server.get("/users/ada%20lovelace").text(), "user ada lovelace");
+}
+
+#[test]
+fn not_found() {
+  let server = TestServer::start(router());
+  let response = server.get("/nowhere");
+  assert_eq!(response.status, 404);
+  assert_eq!(response.text(), "404 Not Found\n");
+  assert_eq!(server.get("/users/42/extra").status, 404);
+
+  let mut custom = router();
+  custom.not_found(|req| Response::text(format!("no {} here", req.path())).with_status(404));
+  let server = TestServer::start(custom);
+  let response = server.get("/nowhere");
+  assert_eq!((response.status, response.text()), (404, "no /nowhere here"));
+}
+
+#[test]
+fn method_not_allowed() {
+  let server = TestServer::start(router());
+  let response = server.client().request("PUT", "/users/42", &[], b"{}");
+  assert_eq!(response.status, 405);
+  assert_eq!(response.header("Allow"), Some("GET, DELETE, HEAD"));
+}
+
+#[test]
+fn head_requests() {
+  let server = TestServer::start(router());
+  let mut client = server.client();
+  let response = client.request("HEAD", "/users/42", &[], b"");
+  assert_eq!(response.status, 200);
+  assert_eq!(response.header("Content-Length"), Some("7")); // "user 42", which isn't sent
+  assert_eq!(client.get("/").text(), "home"); // nothing left over on the connection
+}
+
+#[test]
+fn middleware_and_static_files() {
+  let mut router = router();
+  let files = StaticFiles::new("public");
+  router.get("/*path", move |req| files.serve(req)); // what the routes above don't take
+  let server = TestServer::start_with(router, |server| server.middleware(RequestId::new()));
+
+  let index = server.get("/index.html");
+  assert_eq!(index.status, 200);
+  assert_eq!(index.header("Content-Type"), Some("text/html; charset=utf-8"));
+  assert!(index.header("X-Request-Id").is_some());
+  assert_eq!(index.body, std::fs::read("public/index.html").unwrap());
+
+  assert_eq!(server.get("/users/42").text(), "user 42"); // routes still come first
+  assert_eq!(server.get("/missing.html").status, 404);
+  assert_eq!(server.get("/%2e%2e/Cargo.toml").status, 403); // no way out of the root
+}
@@ -0,0 +1,111 @@
+/*
+stopping: the server finishing what it started before run() returns, and the ThreadPool underneath
+it running or abandoning its jobs
+*/
+
+mod common;
+
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::TestServer;
+use web_server::{ExecuteError, OverflowPolicy, Response, Router, ThreadPool};
+
+// a router whose /slow handler says when it has started, then takes `delay` to answer
+fn slow_router(delay: Duration) -> (Router, mpsc::Receiver<()>) {
+  let (started, receiver) = mpsc::channel();
+  let started = Mutex::new(started);
+  let mut router = Router::new();
+  router.get("/slow", move |_req| {
+    started.lock().unwrap().send(()).unwrap();
+    thread::sleep(delay);
+    Response::text("done")
+  });
+  (router, receiver)
+}
+
+#[test]
+fn finishes_in_flight_requests() {
+  let (router, started) = slow_router(Duration::from_millis(300));
+  let server = TestServer::start(router);
+  let addr = server.addr();
+
+  let mut client = server.client();
+  client.send(b"GET /slow HTTP/1.1\r\n\r\n");
+  started.recv_timeout(Duration::from_secs(5)).unwrap();
+  server.shutdown();
+
+  let response = client.read_response();
+  assert_eq!((response.status, response.text()), (200, "done"));
+  assert_eq!(response.header("Connection"), Some("close")); // no more requests on this one
+  assert!(server.stop().is_ok());
+
+  let refused = TcpStream::connect(addr).unwrap_err();
+  assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn gives_up_on_stuck_requests() {
+  let (router, started) = slow_router(Duration::from_secs(3));
+  let server = TestServer::start_with(router, |server| server.shutdown_timeout(Duration::from_millis(200)));
+
+  let mut client = server.client();
+  client.send(b"GET /slow HTTP/1.1\r\n\r\n");
+  started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+  let stopping = Instant::now();
+  assert!(server.stop().is_ok());
+  assert!(stopping.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn pool_runs_queued_jobs_before_stopping() {
+  let pool = ThreadPool::new(2);
+  let done = Arc::new(AtomicUsize::new(0));
+  for _ in 0..20 {
+    let done = Arc::clone(&done);
+    pool.execute(move || {
+      thread::sleep(Duration::from_millis(5));
+      done.fetch_add(1, Ordering::SeqCst);
+    }).unwrap();
+  }
+  let answer = pool.spawn(|| 6 * 7).unwrap();
+
+  drop(pool); // waits for the queue to drain
+  assert_eq!(done.load(Ordering::SeqCst), 20);
+  assert_eq!(answer.join().unwrap(), 42);
+}
+
+#[test]
+fn closed_pools_refuse_jobs() {
+  let pool = ThreadPool::new(1);
+  pool.close();
+  assert!(matches!(pool.execute(|| {}), Err(ExecuteError::ShuttingDown)));
+
+  let pool = ThreadPool::new(1);
+  pool.execute(|| thread::sleep(Duration::from_secs(3))).unwrap();
+  let stopping = Instant::now();
+  assert!(!pool.shutdown_timeout(Duration::from_millis(100))); // the job was still running
+  assert!(stopping.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn full_queues_dont_hold_up_shutdown() {
+  let pool = ThreadPool::builder(1).queue_capacity(1, OverflowPolicy::Block).build().unwrap();
+  let (started, has_started) = mpsc::channel();
+  pool.execute(move || {
+    started.send(()).unwrap();
+    thread::sleep(Duration::from_secs(3)); // stuck, as far as shutdown is concerned
+  }).unwrap();
+  has_started.recv().unwrap();
+  pool.execute(|| {}).unwrap(); // and now there's no room for the worker's Terminate
+
+  let stopping = Instant::now();
+  assert!(!pool.shutdown_timeout(Duration::from_millis(100)));
+  assert!(stopping.elapsed() < Duration::from_secs(2));
+}